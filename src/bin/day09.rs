@@ -87,11 +87,8 @@ fn parse_input(input: &str) -> Result<HeightMap> {
 fn part_a(heights: &HeightMap) -> u32 {
     (0..heights.width)
         .cartesian_product(0..heights.length)
-        .filter_map(|pos| {
-            heights
-                .is_low_point(pos)
-                .then(|| heights.get_height(pos) + 1)
-        })
+        .filter(|&pos| heights.is_low_point(pos))
+        .map(|pos| heights.get_height(pos) + 1)
         .sum::<u32>()
 }
 
@@ -1,10 +1,19 @@
+use std::{
+    collections::VecDeque,
+    fmt,
+    io::{self, BufReader, Read},
+};
+
 use anyhow::{anyhow, Result};
 use bitvec::{field::BitField as _, prelude as bv};
 use itertools::Itertools;
 use nom::{
-    bytes::complete::take,
-    combinator::map,
+    branch::alt,
+    bytes::complete::{tag, take},
+    character::complete::{alpha1, digit1, multispace0, multispace1},
+    combinator::{all_consuming, map, map_res, opt},
     multi::{count, many0},
+    sequence::{delimited, pair, preceded},
     Finish, IResult,
 };
 use nom_bitvec::BSlice;
@@ -16,9 +25,83 @@ struct PacketHeader {
     type_id: u8,
 }
 
-#[derive(Debug, Clone)]
+/// A literal value of any width, stored as its 4-bit groups with the most significant first.
+/// Leading zero groups are dropped, so equal values compare equal however they were encoded.
+#[derive(Debug, Clone, PartialEq)]
+struct Literal {
+    groups: Vec<u8>,
+}
+
+impl Literal {
+    fn new(groups: impl IntoIterator<Item = u8>) -> Self {
+        let groups = groups.into_iter().skip_while(|g| *g == 0).collect();
+        Literal { groups }
+    }
+
+    /// Returns the value, or `None` if it doesn't fit in a u128.
+    fn to_u128(&self) -> Option<u128> {
+        (self.groups.len() <= 32)
+            .then(|| self.groups.iter().fold(0, |acc, g| acc << 4 | *g as u128))
+    }
+}
+
+impl From<u128> for Literal {
+    fn from(val: u128) -> Self {
+        Literal::new((0..32).rev().map(|i| (val >> (i * 4)) as u8 & 0xf))
+    }
+}
+
+impl fmt::Display for Literal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Repeatedly divide the groups by 10 to find each decimal digit, least significant first.
+        let mut groups = self.groups.clone();
+        let mut digits = Vec::new();
+        while !groups.is_empty() {
+            let mut remainder = 0;
+            for group in &mut groups {
+                let val = remainder * 16 + *group;
+                *group = val / 10;
+                remainder = val % 10;
+            }
+            digits.push(char::from(b'0' + remainder));
+            groups = Literal::new(groups).groups;
+        }
+        if digits.is_empty() {
+            digits.push('0');
+        }
+
+        write!(f, "{}", digits.iter().rev().collect::<String>())
+    }
+}
+
+impl std::str::FromStr for Literal {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        // Groups are built least significant first, multiplying by 10 for each digit.
+        let mut groups: Vec<u8> = Vec::new();
+        for c in s.chars() {
+            let mut carry = c
+                .to_digit(10)
+                .ok_or_else(|| anyhow!("Invalid digit {}", c))? as u8;
+            for group in &mut groups {
+                let val = *group as u32 * 10 + carry as u32;
+                *group = (val & 0xf) as u8;
+                carry = (val >> 4) as u8;
+            }
+            while carry > 0 {
+                groups.push(carry & 0xf);
+                carry >>= 4;
+            }
+        }
+
+        Ok(Literal::new(groups.into_iter().rev()))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 enum PacketType {
-    Literal(usize),
+    Literal(Literal),
     Sum,
     Product,
     Minimum,
@@ -28,13 +111,130 @@ enum PacketType {
     EqualTo,
 }
 
-#[derive(Debug, Clone)]
+impl PacketType {
+    /// The name of an operator in the expression syntax.
+    fn name(&self) -> &'static str {
+        match self {
+            PacketType::Literal(_) => "literal",
+            PacketType::Sum => "sum",
+            PacketType::Product => "product",
+            PacketType::Minimum => "min",
+            PacketType::Maximum => "max",
+            PacketType::GreaterThan => "gt",
+            PacketType::LessThan => "lt",
+            PacketType::EqualTo => "eq",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        let packet_type = match name {
+            "sum" => PacketType::Sum,
+            "product" => PacketType::Product,
+            "min" => PacketType::Minimum,
+            "max" => PacketType::Maximum,
+            "gt" => PacketType::GreaterThan,
+            "lt" => PacketType::LessThan,
+            "eq" => PacketType::EqualTo,
+            _ => return None,
+        };
+        Some(packet_type)
+    }
+
+    fn type_id(&self) -> u8 {
+        match self {
+            PacketType::Sum => 0,
+            PacketType::Product => 1,
+            PacketType::Minimum => 2,
+            PacketType::Maximum => 3,
+            PacketType::Literal(_) => 4,
+            PacketType::GreaterThan => 5,
+            PacketType::LessThan => 6,
+            PacketType::EqualTo => 7,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 struct Packet {
     version: u8,
     packet_type: PacketType,
     subpackets: Vec<Packet>,
 }
 
+/// Formats the packet as an S-expression such as `(sum@1 (product@4 3@2 4@7) 9@0)`, where each
+/// value and operator is annotated with its packet's version. The alternate form `{:#}` omits the
+/// versions, giving `(sum (product 3 4) 9)`.
+impl fmt::Display for Packet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.packet_type {
+            PacketType::Literal(val) => write!(f, "{}", val)?,
+            _ => write!(f, "({}", self.packet_type.name())?,
+        }
+        if !f.alternate() {
+            write!(f, "@{}", self.version)?;
+        }
+        if let PacketType::Literal(_) = self.packet_type {
+            return Ok(());
+        }
+
+        for subpacket in &self.subpackets {
+            write!(f, " ")?;
+            fmt::Display::fmt(subpacket, f)?;
+        }
+        write!(f, ")")
+    }
+}
+
+fn parse_version(input: &str) -> IResult<&str, u8> {
+    map(
+        opt(preceded(tag("@"), map_res(digit1, str::parse::<u8>))),
+        Option::unwrap_or_default,
+    )(input)
+}
+
+fn parse_expression(input: &str) -> IResult<&str, Packet> {
+    alt((
+        map(
+            pair(map_res(digit1, str::parse::<Literal>), parse_version),
+            |(val, version)| Packet {
+                version,
+                packet_type: PacketType::Literal(val),
+                subpackets: Vec::new(),
+            },
+        ),
+        map(
+            delimited(
+                pair(tag("("), multispace0),
+                pair(
+                    pair(
+                        map_res(alpha1, |name| {
+                            PacketType::from_name(name).ok_or_else(|| anyhow!("Unknown operator"))
+                        }),
+                        parse_version,
+                    ),
+                    many0(preceded(multispace1, parse_expression)),
+                ),
+                pair(multispace0, tag(")")),
+            ),
+            |((packet_type, version), subpackets)| Packet {
+                version,
+                packet_type,
+                subpackets,
+            },
+        ),
+    ))(input)
+}
+
+/// Compiles an S-expression, in the form produced by formatting a packet, to a packet. Versions
+/// may be omitted, in which case they are 0.
+fn compile(source: &str) -> Result<Packet> {
+    let packet = all_consuming(delimited(multispace0, parse_expression, multispace0))(source)
+        .finish()
+        .map_err(|e| anyhow!("Error compiling expression: {:?}", e))?
+        .1;
+    Ok(packet)
+}
+
 fn parse_header(input: BSlice<bv::Msb0, u8>) -> IResult<BSlice<bv::Msb0, u8>, PacketHeader> {
     let (input, version) = map(take(3u8), |bits: BSlice<bv::Msb0, u8>| bits.0.load_be())(input)?;
     let (input, type_id) = map(take(3u8), |bits: BSlice<bv::Msb0, u8>| bits.0.load_be())(input)?;
@@ -43,8 +243,8 @@ fn parse_header(input: BSlice<bv::Msb0, u8>) -> IResult<BSlice<bv::Msb0, u8>, Pa
     Ok((input, header))
 }
 
-fn parse_literal(mut input: BSlice<bv::Msb0, u8>) -> IResult<BSlice<bv::Msb0, u8>, usize> {
-    let mut val: bv::BitVec<bv::Msb0, u8> = bv::BitVec::new();
+fn parse_literal(mut input: BSlice<bv::Msb0, u8>) -> IResult<BSlice<bv::Msb0, u8>, Literal> {
+    let mut groups = Vec::new();
     loop {
         let res = map(take(1u8), |bits: BSlice<bv::Msb0, u8>| bits[0])(input)?;
         input = res.0;
@@ -54,13 +254,13 @@ fn parse_literal(mut input: BSlice<bv::Msb0, u8>) -> IResult<BSlice<bv::Msb0, u8
         input = res.0;
         let bits = res.1;
 
-        val.extend(bits.0);
+        groups.push(bits.0.load_be());
         if !not_last {
             break;
         }
     }
 
-    Ok((input, val.load_be()))
+    Ok((input, Literal::new(groups)))
 }
 
 fn parse_subpackets(mut input: BSlice<bv::Msb0, u8>) -> IResult<BSlice<bv::Msb0, u8>, Vec<Packet>> {
@@ -132,8 +332,8 @@ fn parse_packet(mut input: BSlice<bv::Msb0, u8>) -> IResult<BSlice<bv::Msb0, u8>
     Ok((input, packet))
 }
 
-fn parse_input(input: &str) -> Result<Packet> {
-    let bits: bv::BitVec<bv::Msb0, u8> = input
+fn parse_hex(input: &str) -> Result<bv::BitVec<bv::Msb0, u8>> {
+    input
         .trim_end()
         .chars()
         .chunks(2)
@@ -148,7 +348,11 @@ fn parse_input(input: &str) -> Result<Packet> {
                 Ok(acc | val)
             })
         })
-        .collect::<Result<bv::BitVec<bv::Msb0, u8>>>()?;
+        .collect::<Result<bv::BitVec<bv::Msb0, u8>>>()
+}
+
+fn parse_input(input: &str) -> Result<Packet> {
+    let bits = parse_hex(input)?;
 
     let packet = parse_packet(BSlice(bits.as_bitslice()))
         .finish()
@@ -157,6 +361,488 @@ fn parse_input(input: &str) -> Result<Packet> {
     Ok(packet)
 }
 
+/// A structural problem found in a transmission, with the offset in bits at which it was found.
+#[derive(Debug, Clone, PartialEq)]
+enum Problem {
+    /// The transmission ended partway through a packet.
+    Truncated { offset: usize },
+    /// An operator's 15-bit length field extends past the end of the transmission.
+    LengthPastEnd { offset: usize, length: usize },
+    /// An operator's subpackets overran the length given in its header.
+    LengthMismatch { offset: usize },
+    /// A comparison operator which doesn't have exactly two subpackets.
+    ComparisonArity { offset: usize, count: usize },
+    /// A sum, product, minimum or maximum with no subpackets.
+    EmptyAggregate { offset: usize },
+    /// The bits following the outermost packet are not all zero.
+    NonZeroPadding { offset: usize },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Problem::Truncated { offset } => write!(f, "bit {}: transmission truncated", offset),
+            Problem::LengthPastEnd { offset, length } => write!(
+                f,
+                "bit {}: subpacket length {} runs past the end of the transmission",
+                offset, length
+            ),
+            Problem::LengthMismatch { offset } => {
+                write!(f, "bit {}: subpackets overran their length field", offset)
+            }
+            Problem::ComparisonArity { offset, count } => write!(
+                f,
+                "bit {}: comparison has {} subpackets rather than 2",
+                offset, count
+            ),
+            Problem::EmptyAggregate { offset } => {
+                write!(f, "bit {}: operator has no subpackets", offset)
+            }
+            Problem::NonZeroPadding { offset } => write!(f, "bit {}: padding is not zero", offset),
+        }
+    }
+}
+
+/// Reads a field of the given width, recording a problem if it runs past the end of the bits.
+fn read_field(
+    bits: &bv::BitSlice<bv::Msb0, u8>,
+    offset: &mut usize,
+    width: usize,
+    problems: &mut Vec<Problem>,
+) -> Option<usize> {
+    if *offset + width > bits.len() {
+        problems.push(Problem::Truncated { offset: *offset });
+        return None;
+    }
+    let val = bits[*offset..*offset + width].load_be();
+    *offset += width;
+    Some(val)
+}
+
+/// Checks the structure of the packet starting at the offset, advancing the offset past it. Returns
+/// `None` if the transmission was truncated, in which case nothing further can be checked.
+fn validate_packet(
+    bits: &bv::BitSlice<bv::Msb0, u8>,
+    offset: &mut usize,
+    problems: &mut Vec<Problem>,
+) -> Option<()> {
+    let start = *offset;
+    let _version = read_field(bits, offset, 3, problems)?;
+    let type_id = read_field(bits, offset, 3, problems)?;
+
+    if type_id == 4 {
+        while read_field(bits, offset, 5, problems)? & 0b10000 != 0 {}
+        return Some(());
+    }
+
+    let mut count = 0;
+    if read_field(bits, offset, 1, problems)? == 0 {
+        let length_offset = *offset;
+        let length = read_field(bits, offset, 15, problems)?;
+        let end = *offset + length;
+        if end > bits.len() {
+            problems.push(Problem::LengthPastEnd {
+                offset: length_offset,
+                length,
+            });
+        }
+        while *offset < end {
+            validate_packet(bits, offset, problems)?;
+            count += 1;
+        }
+        if *offset > end {
+            problems.push(Problem::LengthMismatch { offset: end });
+        }
+    } else {
+        count = read_field(bits, offset, 11, problems)?;
+        for _ in 0..count {
+            validate_packet(bits, offset, problems)?;
+        }
+    }
+
+    // Every 3-bit type ID is assigned, so the only problems left are with the subpacket count.
+    match type_id {
+        0..=3 if count == 0 => problems.push(Problem::EmptyAggregate { offset: start }),
+        5..=7 if count != 2 => problems.push(Problem::ComparisonArity {
+            offset: start,
+            count,
+        }),
+        _ => {}
+    }
+
+    Some(())
+}
+
+/// Checks the structure of a hex transmission, returning every problem found rather than
+/// stopping at the first.
+fn validate(input: &str) -> Result<Vec<Problem>> {
+    let bits = parse_hex(input)?;
+    let mut problems = Vec::new();
+
+    let mut offset = 0;
+    if validate_packet(&bits, &mut offset, &mut problems).is_some() {
+        if let Some(padding) = bits[offset..].first_one() {
+            problems.push(Problem::NonZeroPadding {
+                offset: offset + padding,
+            });
+        }
+    }
+
+    Ok(problems)
+}
+
+/// How a stream of packets is encoded.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Encoding {
+    /// Hexadecimal digits, with each line holding a separate transmission.
+    Hex,
+    /// Raw bytes, most significant bit first.
+    Raw,
+}
+
+/// An error encountered while decoding a stream of packets. Offsets are in bits from the start of
+/// the stream.
+#[derive(Debug)]
+enum DecodeError {
+    Io(io::Error),
+    InvalidHexDigit {
+        offset: usize,
+        digit: char,
+    },
+    /// The transmission ended while more bits were required.
+    Truncated {
+        offset: usize,
+    },
+    /// The subpackets of an operator overran the length given in its header.
+    LengthMismatch {
+        offset: usize,
+    },
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::Io(e) => write!(f, "Error reading stream: {}", e),
+            DecodeError::InvalidHexDigit { offset, digit } => {
+                write!(f, "Invalid hex digit {:?} at bit {}", digit, offset)
+            }
+            DecodeError::Truncated { offset } => {
+                write!(f, "Transmission truncated at bit {}", offset)
+            }
+            DecodeError::LengthMismatch { offset } => {
+                write!(f, "Subpackets overran their length field at bit {}", offset)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl From<io::Error> for DecodeError {
+    fn from(e: io::Error) -> Self {
+        DecodeError::Io(e)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Symbol {
+    Bit(bool),
+    LineEnd,
+}
+
+/// Reads bits from a stream on demand, keeping track of how many have been consumed.
+struct BitReader<R: Read> {
+    bytes: io::Bytes<BufReader<R>>,
+    encoding: Encoding,
+    /// Symbols which have been read from the stream but not yet consumed.
+    buffer: VecDeque<Symbol>,
+    offset: usize,
+}
+
+impl<R: Read> BitReader<R> {
+    fn new(reader: R, encoding: Encoding) -> Self {
+        BitReader {
+            bytes: BufReader::new(reader).bytes(),
+            encoding,
+            buffer: VecDeque::new(),
+            offset: 0,
+        }
+    }
+
+    /// Reads the next byte from the stream in to the buffer. Returns false at the end of the stream.
+    fn fill(&mut self) -> Result<bool, DecodeError> {
+        let byte = match self.bytes.next() {
+            Some(byte) => byte?,
+            None => return Ok(false),
+        };
+
+        match self.encoding {
+            Encoding::Raw => {
+                self.buffer
+                    .extend((0..8).rev().map(|i| Symbol::Bit(byte & (1 << i) != 0)));
+            }
+            Encoding::Hex => match byte as char {
+                '\n' => self.buffer.push_back(Symbol::LineEnd),
+                '\r' | ' ' | '\t' => {}
+                c => {
+                    let nibble = c.to_digit(16).ok_or(DecodeError::InvalidHexDigit {
+                        offset: self.offset + self.buffered_bits(),
+                        digit: c,
+                    })?;
+                    self.buffer
+                        .extend((0..4).rev().map(|i| Symbol::Bit(nibble & (1 << i) != 0)));
+                }
+            },
+        }
+
+        Ok(true)
+    }
+
+    fn buffered_bits(&self) -> usize {
+        self.buffer
+            .iter()
+            .filter(|s| matches!(s, Symbol::Bit(_)))
+            .count()
+    }
+
+    fn read_bit(&mut self) -> Result<bool, DecodeError> {
+        while self.buffer.is_empty() {
+            if !self.fill()? {
+                return Err(DecodeError::Truncated {
+                    offset: self.offset,
+                });
+            }
+        }
+
+        match self.buffer.pop_front() {
+            Some(Symbol::Bit(bit)) => {
+                self.offset += 1;
+                Ok(bit)
+            }
+            _ => Err(DecodeError::Truncated {
+                offset: self.offset,
+            }),
+        }
+    }
+
+    fn read_bits(&mut self, count: usize) -> Result<usize, DecodeError> {
+        (0..count).try_fold(0, |acc, _| Ok(acc << 1 | self.read_bit()? as usize))
+    }
+
+    /// Skips the zero bits padding the end of a transmission, if the remainder of the line or
+    /// stream consists only of zeroes. Returns false once the end of the stream has been reached.
+    fn skip_padding(&mut self) -> Result<bool, DecodeError> {
+        let mut zeroes = 0;
+        loop {
+            match self.buffer.get(zeroes) {
+                Some(Symbol::Bit(false)) => zeroes += 1,
+                Some(Symbol::Bit(true)) => return Ok(true),
+                Some(Symbol::LineEnd) => {
+                    self.buffer.drain(..=zeroes);
+                    self.offset += zeroes;
+                    zeroes = 0;
+                }
+                None => {
+                    if !self.fill()? {
+                        self.buffer.clear();
+                        self.offset += zeroes;
+                        return Ok(false);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Decodes packets one at a time from a stream which may hold any number of transmissions.
+/// Packets within a transmission may follow one another directly, and a transmission may be
+/// followed by zero bits as padding.
+struct PacketDecoder<R: Read> {
+    reader: BitReader<R>,
+    failed: bool,
+}
+
+impl<R: Read> PacketDecoder<R> {
+    fn new(reader: R, encoding: Encoding) -> Self {
+        PacketDecoder {
+            reader: BitReader::new(reader, encoding),
+            failed: false,
+        }
+    }
+
+    fn decode_packet(&mut self) -> Result<Packet, DecodeError> {
+        let version = self.reader.read_bits(3)? as u8;
+        let type_id = self.reader.read_bits(3)? as u8;
+
+        if type_id == 4 {
+            let mut groups = Vec::new();
+            loop {
+                let not_last = self.reader.read_bit()?;
+                groups.push(self.reader.read_bits(4)? as u8);
+                if !not_last {
+                    break;
+                }
+            }
+
+            return Ok(Packet {
+                version,
+                packet_type: PacketType::Literal(Literal::new(groups)),
+                subpackets: Vec::new(),
+            });
+        }
+
+        let mut subpackets = Vec::new();
+        if self.reader.read_bit()? {
+            let num_sub_packets = self.reader.read_bits(11)?;
+            for _ in 0..num_sub_packets {
+                subpackets.push(self.decode_packet()?);
+            }
+        } else {
+            let sub_packet_length = self.reader.read_bits(15)?;
+            let end = self.reader.offset + sub_packet_length;
+            while self.reader.offset < end {
+                subpackets.push(self.decode_packet()?);
+            }
+            if self.reader.offset > end {
+                return Err(DecodeError::LengthMismatch {
+                    offset: self.reader.offset,
+                });
+            }
+        }
+
+        let packet_type = match type_id {
+            0 => PacketType::Sum,
+            1 => PacketType::Product,
+            2 => PacketType::Minimum,
+            3 => PacketType::Maximum,
+            5 => PacketType::GreaterThan,
+            6 => PacketType::LessThan,
+            7 => PacketType::EqualTo,
+            _ => unreachable!("type id is 3 bits"),
+        };
+
+        Ok(Packet {
+            version,
+            packet_type,
+            subpackets,
+        })
+    }
+}
+
+impl<R: Read> Iterator for PacketDecoder<R> {
+    type Item = Result<Packet, DecodeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+
+        let res = self
+            .reader
+            .skip_padding()
+            .and_then(|more| more.then(|| self.decode_packet()).transpose())
+            .transpose();
+        self.failed = matches!(res, Some(Err(_)));
+        res
+    }
+}
+
+/// How an operator packet's header describes the extent of its subpackets.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum LengthType {
+    /// Length type ID 0, a 15-bit total length in bits.
+    TotalBits,
+    /// Length type ID 1, an 11-bit number of subpackets.
+    SubpacketCount,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct EncodeOptions {
+    /// The length type to use for every operator packet, or `None` to use whichever gives the
+    /// shortest encoding.
+    length_type: Option<LengthType>,
+    /// The minimum number of groups to split literal values in to, padding with leading zero
+    /// groups. Literals always use as many groups as they need.
+    min_literal_groups: usize,
+}
+
+fn push_bits(bits: &mut bv::BitVec<bv::Msb0, u8>, val: usize, width: usize) {
+    bits.extend((0..width).rev().map(|i| val & (1 << i) != 0));
+}
+
+fn encode_packet(
+    packet: &Packet,
+    options: &EncodeOptions,
+    bits: &mut bv::BitVec<bv::Msb0, u8>,
+) -> Result<()> {
+    if packet.version >= 8 {
+        return Err(anyhow!("Version {} does not fit in 3 bits", packet.version));
+    }
+    push_bits(bits, packet.version as usize, 3);
+    push_bits(bits, packet.packet_type.type_id() as usize, 3);
+
+    if let PacketType::Literal(val) = &packet.packet_type {
+        let padding = options
+            .min_literal_groups
+            .max(1)
+            .saturating_sub(val.groups.len());
+        let groups = std::iter::repeat_n(0, padding).chain(val.groups.iter().cloned());
+        let num_groups = padding + val.groups.len();
+        for (i, group) in groups.enumerate() {
+            bits.push(i + 1 != num_groups);
+            push_bits(bits, group as usize, 4);
+        }
+        return Ok(());
+    }
+
+    let mut subpacket_bits = bv::BitVec::new();
+    for subpacket in &packet.subpackets {
+        encode_packet(subpacket, options, &mut subpacket_bits)?;
+    }
+
+    let count = packet.subpackets.len();
+    let length = subpacket_bits.len();
+    let length_type = match options.length_type {
+        Some(length_type) => length_type,
+        None if count < 1 << 11 => LengthType::SubpacketCount,
+        None => LengthType::TotalBits,
+    };
+    match length_type {
+        LengthType::TotalBits if length < 1 << 15 => {
+            bits.push(false);
+            push_bits(bits, length, 15);
+        }
+        LengthType::SubpacketCount if count < 1 << 11 => {
+            bits.push(true);
+            push_bits(bits, count, 11);
+        }
+        LengthType::TotalBits => {
+            return Err(anyhow!(
+                "Subpackets of length {} do not fit in 15 bits",
+                length
+            ))
+        }
+        LengthType::SubpacketCount => {
+            return Err(anyhow!("{} subpackets do not fit in 11 bits", count))
+        }
+    }
+    bits.extend(subpacket_bits);
+
+    Ok(())
+}
+
+/// Encodes a packet as a hex string, padded with zero bits to a whole number of bytes.
+fn encode(packet: &Packet, options: &EncodeOptions) -> Result<String> {
+    let mut bits = bv::BitVec::<bv::Msb0, u8>::new();
+    encode_packet(packet, options, &mut bits)?;
+    bits.resize(bits.len().div_ceil(8) * 8, false);
+
+    Ok(bits
+        .chunks(4)
+        .map(|nibble| format!("{:X}", nibble.load_be::<u8>()))
+        .collect())
+}
+
 fn sum_versions(packet: &Packet) -> usize {
     match packet.packet_type {
         PacketType::Literal(_) => packet.version as usize,
@@ -164,25 +850,43 @@ fn sum_versions(packet: &Packet) -> usize {
     }
 }
 
-fn evaluate(packet: &Packet) -> usize {
-    match packet.packet_type {
-        PacketType::Literal(val) => val,
-        PacketType::Sum => packet.subpackets.iter().map(evaluate).sum(),
-        PacketType::Product => packet.subpackets.iter().map(evaluate).product(),
-        PacketType::Minimum => packet.subpackets.iter().map(evaluate).min().unwrap(),
-        PacketType::Maximum => packet.subpackets.iter().map(evaluate).max().unwrap(),
-        PacketType::GreaterThan => {
-            assert_eq!(packet.subpackets.len(), 2);
-            (evaluate(&packet.subpackets[0]) > evaluate(&packet.subpackets[1])) as _
-        }
-        PacketType::LessThan => {
-            assert_eq!(packet.subpackets.len(), 2);
-            (evaluate(&packet.subpackets[0]) < evaluate(&packet.subpackets[1])) as _
-        }
-        PacketType::EqualTo => {
-            assert_eq!(packet.subpackets.len(), 2);
-            (evaluate(&packet.subpackets[0]) == evaluate(&packet.subpackets[1])) as _
-        }
+/// Evaluates the packet, returning an error if a value overflows a u128 or an operator has the
+/// wrong number of subpackets.
+fn evaluate(packet: &Packet) -> Result<u128> {
+    let values = packet
+        .subpackets
+        .iter()
+        .map(evaluate)
+        .collect::<Result<Vec<_>>>()?;
+    let overflow = || anyhow!("Overflow evaluating {:#}", packet);
+    let empty = || anyhow!("{} has no subpackets", packet.packet_type.name());
+
+    let comparison = |compare: fn(&u128, &u128) -> bool| match values.as_slice() {
+        [lhs, rhs] => Ok(compare(lhs, rhs) as u128),
+        _ => Err(anyhow!(
+            "Comparison {} has {} subpackets rather than 2",
+            packet.packet_type.name(),
+            values.len()
+        )),
+    };
+
+    match &packet.packet_type {
+        PacketType::Literal(val) => val
+            .to_u128()
+            .ok_or_else(|| anyhow!("Literal {} does not fit in a u128", val)),
+        PacketType::Sum => values
+            .iter()
+            .try_fold(0u128, |acc, val| acc.checked_add(*val))
+            .ok_or_else(overflow),
+        PacketType::Product => values
+            .iter()
+            .try_fold(1u128, |acc, val| acc.checked_mul(*val))
+            .ok_or_else(overflow),
+        PacketType::Minimum => values.iter().min().copied().ok_or_else(empty),
+        PacketType::Maximum => values.iter().max().copied().ok_or_else(empty),
+        PacketType::GreaterThan => comparison(u128::gt),
+        PacketType::LessThan => comparison(u128::lt),
+        PacketType::EqualTo => comparison(u128::eq),
     }
 }
 
@@ -190,13 +894,181 @@ fn main() -> Result<()> {
     let input = std::fs::read_to_string("res/day16")?;
     let packet = parse_input(&input)?;
 
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    match args.iter().map(String::as_str).collect_vec().as_slice() {
+        [] => {}
+        ["print"] => {
+            println!("{}", packet);
+            return Ok(());
+        }
+        ["compile", source] => {
+            println!("{}", encode(&compile(source)?, &EncodeOptions::default())?);
+            return Ok(());
+        }
+        ["decode", encoding @ ..] if encoding.len() <= 1 => {
+            let encoding = match encoding {
+                [] | ["hex"] => Encoding::Hex,
+                ["raw"] => Encoding::Raw,
+                _ => return Err(anyhow!("Unknown encoding {:?}", encoding)),
+            };
+            for packet in PacketDecoder::new(io::stdin(), encoding) {
+                let packet = packet?;
+                match evaluate(&packet) {
+                    Ok(value) => println!("{} = {}", packet, value),
+                    Err(e) => println!("{} = {}", packet, e),
+                }
+            }
+            return Ok(());
+        }
+        ["validate", transmission] => {
+            for problem in validate(transmission)? {
+                println!("{}", problem);
+            }
+            return Ok(());
+        }
+        _ => {
+            return Err(anyhow!(
+                "Usage: day16 [print | compile <expression> | decode [hex | raw] | \
+                 validate <transmission>]"
+            ))
+        }
+    }
+
     let result_a = sum_versions(&packet);
     assert_eq!(result_a, 893);
     println!("Day 16, part A: {}", result_a);
 
-    let result_b = evaluate(&packet);
+    let result_b = evaluate(&packet)?;
     assert_eq!(result_b, 4358595186090);
     println!("Day 16, part B: {}", result_b);
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn puzzle_packet() -> Result<Packet> {
+        parse_input(&std::fs::read_to_string("res/day16")?)
+    }
+
+    #[test]
+    fn streaming_decoder() -> Result<()> {
+        // Check the streaming decoder agrees with parsing the whole input up front
+        let streamed = PacketDecoder::new(std::fs::File::open("res/day16")?, Encoding::Hex)
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(streamed, vec![puzzle_packet()?]);
+
+        // Check the streaming decoder handles several transmissions, and packets which follow one
+        // another directly without padding
+        let transmissions = "8A004A801A8002F478\n620080001611562C8802118E34\nD2FE2E97F140\n";
+        let versions = PacketDecoder::new(transmissions.as_bytes(), Encoding::Hex)
+            .map(|packet| packet.map(|packet| sum_versions(&packet)))
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(versions, vec![16, 12, 6, 6]);
+        let raw = [0xD2, 0xFE, 0x28];
+        let literals =
+            PacketDecoder::new(&raw[..], Encoding::Raw).collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(literals[0].packet_type, PacketType::Literal(2021.into()));
+
+        // Check a truncated transmission reports where it ended
+        let mut truncated = PacketDecoder::new("8A004A80\nD2FE28".as_bytes(), Encoding::Hex);
+        assert!(matches!(
+            truncated.next(),
+            Some(Err(DecodeError::Truncated { offset: 32 }))
+        ));
+        assert!(truncated.next().is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn encoder() -> Result<()> {
+        // Check encoding the packet produces a transmission which decodes to the same packet
+        let packet = puzzle_packet()?;
+        let encoded = encode(&packet, &EncodeOptions::default())?;
+        assert_eq!(parse_input(&encoded)?, packet);
+
+        // Check the encoder reproduces the puzzle's examples exactly when configured as they were
+        for (transmission, length_type) in [
+            ("D2FE28", None),
+            ("38006F45291200", Some(LengthType::TotalBits)),
+            ("EE00D40C823060", Some(LengthType::SubpacketCount)),
+        ] {
+            let options = EncodeOptions {
+                length_type,
+                ..Default::default()
+            };
+            assert_eq!(encode(&parse_input(transmission)?, &options)?, transmission);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn compiler() -> Result<()> {
+        // Check packets can be written as expressions and compiled back to the same packet
+        let packet = puzzle_packet()?;
+        assert_eq!(compile(&packet.to_string())?, packet);
+        let program = compile("(sum (product 3 4) (max 1 9))")?;
+        assert_eq!(format!("{:#}", program), "(sum (product 3 4) (max 1 9))");
+        let transmission = encode(&program, &EncodeOptions::default())?;
+        assert_eq!(evaluate(&parse_input(&transmission)?)?, 21);
+
+        Ok(())
+    }
+
+    #[test]
+    fn wide_literals() -> Result<()> {
+        // Check literals wider than 64 bits survive encoding, and overflow is reported as an error
+        let max = u128::MAX.to_string();
+        let wide = compile(&format!("(sum {} 1)", max))?;
+        let transmission = encode(&wide, &EncodeOptions::default())?;
+        assert_eq!(parse_input(&transmission)?, wide);
+        assert_eq!(evaluate(&compile(&format!("(max {} 1)", max))?)?, u128::MAX);
+        assert!(evaluate(&wide).is_err());
+        assert!(evaluate(&compile(
+            "(product 18446744073709551616 18446744073709551616)"
+        )?)
+        .is_err());
+        assert!(evaluate(&compile("340282366920938463463374607431768211456")?).is_err());
+        assert!(evaluate(&compile("(min)")?).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn validator() -> Result<()> {
+        // Check the validator finds no problems with the input, and reports every problem at once
+        // otherwise
+        assert!(validate(&std::fs::read_to_string("res/day16")?)?.is_empty());
+        let malformed = encode(&compile("(gt (sum) 1 2)")?, &EncodeOptions::default())?;
+        assert_eq!(
+            validate(&malformed)?,
+            vec![
+                Problem::EmptyAggregate { offset: 18 },
+                Problem::ComparisonArity {
+                    offset: 0,
+                    count: 3
+                },
+            ]
+        );
+        assert_eq!(
+            validate("D2FE29")?,
+            vec![Problem::NonZeroPadding { offset: 23 }]
+        );
+        assert_eq!(
+            validate("38006F452912")?,
+            vec![
+                Problem::LengthPastEnd {
+                    offset: 7,
+                    length: 27
+                },
+                Problem::Truncated { offset: 44 },
+            ]
+        );
+
+        Ok(())
+    }
+}
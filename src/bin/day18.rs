@@ -1,4 +1,4 @@
-use std::{cell::RefCell, fmt, ops::Add, rc::Rc};
+use std::{convert::TryFrom, fmt, iter::Sum, ops::Add, str::FromStr};
 
 use anyhow::{anyhow, Result};
 use itertools::Itertools;
@@ -6,212 +6,496 @@ use nom::{
     branch::alt,
     bytes::complete::tag,
     character::complete::{digit1, newline},
-    combinator::map_res,
+    combinator::{all_consuming, map_res},
+    error::ErrorKind,
     multi::separated_list1,
     sequence::{preceded, separated_pair, terminated},
-    IResult,
+    Finish, IResult,
 };
+use serde::{Deserialize, Serialize};
 
-#[derive(PartialEq)]
-enum Number {
-    Digit(u32),
-    Pair(Rc<RefCell<Number>>, Rc<RefCell<Number>>),
+/// The parameters of snailfish arithmetic, allowing variants of the puzzle's rules.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct SnailfishRules {
+    /// Pairs nested inside this many others explode.
+    explode_depth: u32,
+    /// Regular numbers of this value or greater split.
+    split_threshold: u32,
+    /// The multipliers for the magnitudes of the left and right of a pair.
+    magnitude_weights: (u32, u32),
+    /// The most pairs a regular number may be nested inside when parsing.
+    max_parse_depth: u32,
 }
 
-impl Add for Number {
-    type Output = Self;
+impl SnailfishRules {
+    /// Creates a set of rules, checking reduction will always finish under them.
+    fn new(
+        explode_depth: u32,
+        split_threshold: u32,
+        magnitude_weights: (u32, u32),
+        max_parse_depth: u32,
+    ) -> Result<Self, RulesError> {
+        // Splitting a 1 gives a 1, and anything will split if the threshold is 0, so reduction
+        // would never end.
+        if split_threshold < 2 {
+            return Err(RulesError::SplitThresholdTooLow { split_threshold });
+        }
 
-    fn add(self, other: Self) -> Self::Output {
-        let mut res = Self::Pair(Rc::new(RefCell::new(self)), Rc::new(RefCell::new(other)));
-        res.reduce();
-        res
+        Ok(SnailfishRules {
+            explode_depth,
+            split_threshold,
+            magnitude_weights,
+            max_parse_depth,
+        })
     }
 }
 
-impl fmt::Debug for Number {
+impl Default for SnailfishRules {
+    /// The rules given by the puzzle. Parsing accepts the deepest nesting that can result from
+    /// adding two reduced numbers.
+    fn default() -> Self {
+        SnailfishRules {
+            explode_depth: 4,
+            split_threshold: 10,
+            magnitude_weights: (3, 2),
+            max_parse_depth: 5,
+        }
+    }
+}
+
+/// An error in a set of snailfish rules which would stop numbers being reduced.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum RulesError {
+    SplitThresholdTooLow { split_threshold: u32 },
+}
+
+impl fmt::Display for RulesError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            Number::Digit(digit) => write!(f, "{}", digit),
-            Number::Pair(left, right) => write!(
+            RulesError::SplitThresholdTooLow { split_threshold } => write!(
                 f,
-                "[{:?},{:?}]",
-                left.as_ref().borrow(),
-                right.as_ref().borrow()
+                "Split threshold must be at least 2 for reduction to finish, not {}",
+                split_threshold
             ),
         }
     }
 }
 
-impl Clone for Number {
-    fn clone(&self) -> Self {
-        match self {
-            Number::Digit(d) => Number::Digit(*d),
-            Number::Pair(a, b) => Number::Pair(
-                Rc::new(RefCell::new(a.borrow().clone())),
-                Rc::new(RefCell::new(b.borrow().clone())),
-            ),
+impl std::error::Error for RulesError {}
+
+/// A regular number within a snailfish number, along with the number of pairs it is nested in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Element {
+    depth: u32,
+    value: u32,
+}
+
+/// A snailfish number, stored as its regular numbers in order from left to right. The structure of
+/// the pairs can be recovered from the depths: a pair is formed from two adjacent elements of the
+/// same depth, or recursively from two adjacent pairs.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "Tree", into = "Tree")]
+struct Number {
+    elements: Vec<Element>,
+}
+
+/// The tree form of a snailfish number, which serializes as nested arrays, e.g. `[[1,2],3]`.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum Tree {
+    Regular(u32),
+    Pair(Box<Tree>, Box<Tree>),
+}
+
+impl TryFrom<Tree> for Number {
+    type Error = NestedTooDeeply;
+
+    /// Converts a tree to a number, keeping to the same nesting limit as parsing one from text.
+    fn try_from(tree: Tree) -> Result<Self, Self::Error> {
+        fn number(tree: Tree) -> Number {
+            match tree {
+                Tree::Regular(value) => Number {
+                    elements: vec![Element { depth: 0, value }],
+                },
+                Tree::Pair(left, right) => number(*left).join(number(*right)),
+            }
+        }
+
+        let max_depth = SnailfishRules::default().max_parse_depth;
+        let number = number(tree);
+        if number
+            .elements
+            .iter()
+            .any(|element| element.depth > max_depth)
+        {
+            return Err(NestedTooDeeply { max_depth });
         }
+
+        Ok(number)
     }
 }
 
-impl Number {
-    fn as_digit(&self) -> u32 {
-        match self {
-            Number::Digit(d) => *d,
-            Number::Pair(_, _) => panic!("Not a digit"),
-        }
+/// An error converting a tree to a snailfish number with pairs nested more deeply than allowed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct NestedTooDeeply {
+    max_depth: u32,
+}
+
+impl fmt::Display for NestedTooDeeply {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Snailfish number nested inside more than {} pairs",
+            self.max_depth
+        )
     }
+}
 
-    fn as_pair(&self) -> (&Rc<RefCell<Number>>, &Rc<RefCell<Number>>) {
-        match self {
-            Number::Digit(_) => panic!("Not a pair"),
-            Number::Pair(left, right) => (left, right),
+impl std::error::Error for NestedTooDeeply {}
+
+impl From<Number> for Tree {
+    fn from(number: Number) -> Self {
+        fn tree_at(elements: &[Element], i: &mut usize, depth: u32) -> Tree {
+            if elements[*i].depth == depth {
+                *i += 1;
+                Tree::Regular(elements[*i - 1].value)
+            } else {
+                let left = tree_at(elements, i, depth + 1);
+                let right = tree_at(elements, i, depth + 1);
+                Tree::Pair(Box::new(left), Box::new(right))
+            }
         }
+
+        tree_at(&number.elements, &mut 0, 0)
     }
+}
 
-    fn try_explode(&mut self) -> bool {
-        let mut prev_digit = None;
-        let mut next_digit = None;
-        let mut to_explode = None;
+impl Add for Number {
+    type Output = Self;
 
-        let mut to_search: Vec<(usize, Rc<RefCell<Number>>)> = match self {
-            Number::Digit(_) => vec![],
-            Number::Pair(a, b) => vec![(1, Rc::clone(b)), (1, Rc::clone(a))],
-        };
+    fn add(self, other: Self) -> Self::Output {
+        let mut res = self.join(other);
+        res.reduce(&SnailfishRules::default(), None);
+        res
+    }
+}
 
-        while let Some((depth, node)) = to_search.pop() {
-            let node_borrow = node.borrow();
-            match &*node_borrow {
-                Number::Digit(_) => {
-                    prev_digit = Some(Rc::clone(&node));
-                }
-                Number::Pair(a, b) => {
-                    let a_borrow = a.borrow();
-                    let b_borrow = b.borrow();
-                    match (&*a_borrow, &*b_borrow) {
-                        (Number::Digit(_), Number::Digit(_)) if depth >= 4 => {
-                            next_digit = loop {
-                                if let Some((_, node)) = to_search.pop() {
-                                    let node_borrow = node.borrow();
-                                    match &*node_borrow {
-                                        Number::Digit(_) => {
-                                            break Some(Rc::clone(&node));
-                                        }
-                                        Number::Pair(a, b) => {
-                                            to_search.push((depth + 1, Rc::clone(b)));
-                                            to_search.push((depth + 1, Rc::clone(a)));
-                                        }
-                                    }
-                                } else {
-                                    break None;
-                                }
-                            };
-
-                            to_explode = Some(Rc::clone(&node));
-                            break;
-                        }
-                        _ => {
-                            to_search.push((depth + 1, Rc::clone(b)));
-                            to_search.push((depth + 1, Rc::clone(a)));
-                        }
-                    }
-                }
+/// Sums the numbers from left to right.
+///
+/// # Panics
+///
+/// Panics if there are no numbers, as snailfish numbers have no zero.
+impl Sum for Number {
+    fn sum<I: Iterator<Item = Self>>(mut iter: I) -> Self {
+        let first = iter.next().expect("Cannot sum zero snailfish numbers");
+        iter.fold(first, |acc, num| acc + num)
+    }
+}
+
+/// Formats the number in the puzzle's notation, e.g. `[[1,2],3]`.
+impl fmt::Display for Number {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fn fmt_at(
+            elements: &[Element],
+            i: &mut usize,
+            depth: u32,
+            f: &mut fmt::Formatter,
+        ) -> fmt::Result {
+            if elements[*i].depth == depth {
+                *i += 1;
+                write!(f, "{}", elements[*i - 1].value)
+            } else {
+                write!(f, "[")?;
+                fmt_at(elements, i, depth + 1, f)?;
+                write!(f, ",")?;
+                fmt_at(elements, i, depth + 1, f)?;
+                write!(f, "]")
             }
         }
 
-        if let Some(node) = to_explode {
-            let mut node_borrow = node.borrow_mut();
+        fmt_at(&self.elements, &mut 0, 0, f)
+    }
+}
+
+impl fmt::Debug for Number {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+/// An error parsing a snailfish number, giving the offset in characters of the problem.
+#[derive(Debug, Clone, PartialEq)]
+enum ParseNumberError {
+    Invalid {
+        offset: usize,
+    },
+    /// A pair is nested more deeply than the rules allow.
+    TooDeep {
+        offset: usize,
+    },
+}
 
-            if let Some(prev_digit) = prev_digit {
-                let mut prev_borrow = prev_digit.borrow_mut();
-                *prev_borrow = Number::Digit(
-                    prev_borrow.as_digit() + node_borrow.as_pair().0.borrow().as_digit(),
-                );
+impl fmt::Display for ParseNumberError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseNumberError::Invalid { offset } => {
+                write!(f, "Invalid snailfish number at character {}", offset)
             }
-            if let Some(next_digit) = next_digit {
-                let mut next_borrow = next_digit.borrow_mut();
-                *next_borrow = Number::Digit(
-                    next_borrow.as_digit() + node_borrow.as_pair().1.borrow().as_digit(),
-                );
+            ParseNumberError::TooDeep { offset } => {
+                write!(
+                    f,
+                    "Snailfish number nested too deeply at character {}",
+                    offset
+                )
             }
+        }
+    }
+}
+
+impl std::error::Error for ParseNumberError {}
+
+impl FromStr for Number {
+    type Err = ParseNumberError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Number::parse(s, &SnailfishRules::default())
+    }
+}
 
-            *node_borrow = Number::Digit(0);
+/// Finds the first element matching the predicate, which is given the element's index and its
+/// route from the outermost pair, as the side of each enclosing pair it is on with true meaning
+/// right. Returns the element's index and route.
+fn find_element<P>(elements: &[Element], predicate: P) -> Option<(usize, Vec<bool>)>
+where
+    P: Fn(usize, &[bool]) -> bool,
+{
+    let mut path: Vec<bool> = Vec::new();
+    for (i, element) in elements.iter().enumerate() {
+        if i > 0 {
+            // Step from the previous element to this one: climb out of every pair it was on the
+            // right of, then move over to the right of the next.
+            while path.last() == Some(&true) {
+                path.pop();
+            }
+            if let Some(side) = path.last_mut() {
+                *side = true;
+            }
+        }
+        path.resize(element.depth as usize, false);
 
-            true
-        } else {
-            false
+        if predicate(i, &path) {
+            return Some((i, path));
         }
     }
 
-    fn try_split(&mut self) -> bool {
-        let mut to_search: Vec<Rc<RefCell<Number>>> = match self {
-            Number::Digit(_) => vec![],
-            Number::Pair(a, b) => vec![Rc::clone(b), Rc::clone(a)],
+    None
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Action {
+    Explode,
+    Split,
+}
+
+/// A record of a single explode or split performed while reducing a number.
+#[derive(Debug, Clone)]
+struct ReductionStep {
+    action: Action,
+    /// The route from the outermost pair to the pair which exploded or the number which split,
+    /// as the side taken at each level, with true meaning right.
+    path: Vec<bool>,
+    before: Number,
+    after: Number,
+}
+
+impl fmt::Display for ReductionStep {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let action = match self.action {
+            Action::Explode => "explode",
+            Action::Split => "split",
         };
+        let path = self
+            .path
+            .iter()
+            .map(|right| if *right { 'R' } else { 'L' })
+            .collect::<String>();
+        write!(
+            f,
+            "{} at {}: {} -> {}",
+            action, path, self.before, self.after
+        )
+    }
+}
 
-        while let Some(node) = to_search.pop() {
-            let mut node_borrow = node.borrow_mut();
-            match &*node_borrow {
-                Number::Digit(d) => {
-                    if *d >= 10 {
-                        let val = node_borrow.as_digit();
-                        let left = Rc::new(RefCell::new(Number::Digit(val / 2)));
-                        let right = Rc::new(RefCell::new(Number::Digit((val + 1) / 2)));
-                        *node_borrow = Number::Pair(left, right);
-                        return true;
-                    }
-                }
-                Number::Pair(a, b) => {
-                    to_search.push(Rc::clone(b));
-                    to_search.push(Rc::clone(a));
+impl Number {
+    fn parse(s: &str, rules: &SnailfishRules) -> Result<Self, ParseNumberError> {
+        let number = all_consuming(|input| parse_number(input, 0, rules.max_parse_depth))(s)
+            .finish()
+            .map_err(|e| {
+                let offset = s.len() - e.input.len();
+                match e.code {
+                    ErrorKind::TooLarge => ParseNumberError::TooDeep { offset },
+                    _ => ParseNumberError::Invalid { offset },
                 }
+            })?
+            .1;
+
+        Ok(number)
+    }
+
+    /// Explodes the leftmost pair nested inside enough others, returning the path to it.
+    fn try_explode(&mut self, rules: &SnailfishRules) -> Option<Vec<bool>> {
+        let (i, mut path) = find_element(&self.elements, |i, path| {
+            // An element on the left of a pair whose right is also a regular number.
+            let depth = self.elements[i].depth;
+            path.last() == Some(&false)
+                && self.elements.get(i + 1).map(|e| e.depth) == Some(depth)
+                && depth > rules.explode_depth
+        })?;
+
+        let Element { depth, value: left } = self.elements[i];
+        let right = self.elements[i + 1].value;
+        if i > 0 {
+            self.elements[i - 1].value += left;
+        }
+        if let Some(next) = self.elements.get_mut(i + 2) {
+            next.value += right;
+        }
+        self.elements.splice(
+            i..i + 2,
+            [Element {
+                depth: depth - 1,
+                value: 0,
+            }],
+        );
+
+        path.pop();
+        Some(path)
+    }
+
+    /// Splits the leftmost regular number large enough to, returning the path to it.
+    fn try_split(&mut self, rules: &SnailfishRules) -> Option<Vec<bool>> {
+        let (i, path) = find_element(&self.elements, |i, _| {
+            self.elements[i].value >= rules.split_threshold
+        })?;
+
+        let Element { depth, value } = self.elements[i];
+        self.elements.splice(
+            i..i + 1,
+            [
+                Element {
+                    depth: depth + 1,
+                    value: value / 2,
+                },
+                Element {
+                    depth: depth + 1,
+                    value: value.div_ceil(2),
+                },
+            ],
+        );
+
+        Some(path)
+    }
+
+    /// Reduces the number, recording each step taken in the log if one is given.
+    fn reduce(&mut self, rules: &SnailfishRules, mut log: Option<&mut Vec<ReductionStep>>) {
+        loop {
+            let before = log.as_ref().map(|_| self.clone());
+            let (action, path) = if let Some(path) = self.try_explode(rules) {
+                (Action::Explode, path)
+            } else if let Some(path) = self.try_split(rules) {
+                (Action::Split, path)
+            } else {
+                break;
+            };
+
+            if let (Some(log), Some(before)) = (log.as_mut(), before) {
+                log.push(ReductionStep {
+                    action,
+                    path,
+                    before,
+                    after: self.clone(),
+                });
             }
         }
+    }
 
-        false
+    /// Adds two numbers, returning the result along with each step taken to reduce it.
+    fn add_logged(self, other: Self, rules: &SnailfishRules) -> (Self, Vec<ReductionStep>) {
+        let mut res = self.join(other);
+        let mut log = Vec::new();
+        res.reduce(rules, Some(&mut log));
+        (res, log)
     }
 
-    fn reduce(&mut self) {
-        while self.try_explode() || self.try_split() {}
+    /// Forms a pair from two numbers without reducing it.
+    fn join(self, other: Self) -> Self {
+        let mut elements = self.elements;
+        elements.extend(other.elements);
+        for element in &mut elements {
+            element.depth += 1;
+        }
+        Number { elements }
     }
 
-    fn magnitude(&self) -> u32 {
-        match self {
-            Number::Digit(d) => *d,
-            Number::Pair(left, right) => {
-                left.borrow().magnitude() * 3 + right.borrow().magnitude() * 2
+    fn magnitude(&self, rules: &SnailfishRules) -> u32 {
+        fn magnitude_at(
+            elements: &[Element],
+            i: &mut usize,
+            depth: u32,
+            weights: (u32, u32),
+        ) -> u32 {
+            if elements[*i].depth == depth {
+                *i += 1;
+                elements[*i - 1].value
+            } else {
+                magnitude_at(elements, i, depth + 1, weights) * weights.0
+                    + magnitude_at(elements, i, depth + 1, weights) * weights.1
             }
         }
+
+        magnitude_at(&self.elements, &mut 0, 0, rules.magnitude_weights)
     }
 }
 
 fn parse_digit(input: &str) -> IResult<&str, Number> {
-    let (input, digit) = map_res(digit1, str::parse::<u32>)(input)?;
+    let (input, value) = map_res(digit1, str::parse::<u32>)(input)?;
 
-    Ok((input, Number::Digit(digit)))
+    Ok((
+        input,
+        Number {
+            elements: vec![Element { depth: 0, value }],
+        },
+    ))
 }
 
-fn parse_pair(input: &str) -> IResult<&str, Number> {
-    let (input, pair) = terminated(
-        preceded(
-            tag("["),
-            separated_pair(parse_number, tag(","), parse_number),
-        ),
+/// Parses a pair nested inside `depth` others, failing at its opening bracket if that is as deep
+/// as `max_depth`. The depth is checked before parsing the pair's contents, so deeply nested input
+/// can't exhaust the stack.
+fn parse_pair(input: &str, depth: u32, max_depth: u32) -> IResult<&str, Number> {
+    if depth >= max_depth && input.starts_with('[') {
+        return Err(nom::Err::Failure(nom::error::Error::new(
+            input,
+            ErrorKind::TooLarge,
+        )));
+    }
+
+    let inner = move |input| parse_number(input, depth + 1, max_depth);
+    let (input, (left, right)) = terminated(
+        preceded(tag("["), separated_pair(inner, tag(","), inner)),
         tag("]"),
     )(input)?;
 
-    Ok((
-        input,
-        Number::Pair(Rc::new(RefCell::new(pair.0)), Rc::new(RefCell::new(pair.1))),
-    ))
+    Ok((input, left.join(right)))
 }
 
-fn parse_number(input: &str) -> IResult<&str, Number> {
-    alt((parse_digit, parse_pair))(input)
+fn parse_number(input: &str, depth: u32, max_depth: u32) -> IResult<&str, Number> {
+    alt((parse_digit, |input| parse_pair(input, depth, max_depth)))(input)
 }
 
 fn parse_input(input: &str) -> IResult<&str, Vec<Number>> {
-    separated_list1(newline, parse_number)(input)
+    let max_depth = SnailfishRules::default().max_parse_depth;
+    separated_list1(newline, move |input| parse_number(input, 0, max_depth))(input)
 }
 
 fn main() -> Result<()> {
@@ -219,18 +503,48 @@ fn main() -> Result<()> {
     let numbers = parse_input(&input)
         .map_err(|e| anyhow!("Error parsing input: {:?}", e))?
         .1;
+    let rules = SnailfishRules::default();
 
-    let result_a = numbers[1..]
-        .iter()
-        .fold(numbers[0].clone(), |acc, num| acc + num.clone())
-        .magnitude();
+    let args = std::env::args().skip(1).collect_vec();
+    match args.iter().map(String::as_str).collect_vec().as_slice() {
+        [] => {}
+        ["add", lhs, rhs, rest @ ..] if matches!(rest.len(), 0 | 2) => {
+            // Optionally reduce under different rules, parsing as deeply as they allow
+            let rules = match rest {
+                [explode_depth, split_threshold] => {
+                    let explode_depth = explode_depth.parse()?;
+                    SnailfishRules::new(
+                        explode_depth,
+                        split_threshold.parse()?,
+                        rules.magnitude_weights,
+                        explode_depth + 1,
+                    )?
+                }
+                _ => rules,
+            };
+            let (lhs, rhs) = (Number::parse(lhs, &rules)?, Number::parse(rhs, &rules)?);
+            let (sum, log) = lhs.add_logged(rhs, &rules);
+            for step in log {
+                println!("{}", step);
+            }
+            println!("{}", sum);
+            return Ok(());
+        }
+        _ => {
+            return Err(anyhow!(
+                "Usage: day18 [add <number> <number> [<explode depth> <split threshold>]]"
+            ))
+        }
+    }
+
+    let result_a = numbers.iter().cloned().sum::<Number>().magnitude(&rules);
     assert_eq!(result_a, 3051);
     println!("Day 18, part A: {}", result_a);
 
     let result_b = numbers
         .into_iter()
         .permutations(2)
-        .map(|p| (p[0].clone() + p[1].clone()).magnitude())
+        .map(|p| (p[0].clone() + p[1].clone()).magnitude(&rules))
         .max()
         .unwrap();
     assert_eq!(result_b, 4812);
@@ -238,3 +552,117 @@ fn main() -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn examples() -> Result<()> {
+        let rules = SnailfishRules::default();
+
+        let mut exploded = "[[6,[5,[4,[3,2]]]],1]".parse::<Number>()?;
+        assert_eq!(
+            exploded.try_explode(&rules),
+            Some(vec![false, true, true, true])
+        );
+        assert_eq!(exploded, "[[6,[5,[7,0]]],3]".parse::<Number>()?);
+        let lhs = "[[[[4,3],4],4],[7,[[8,4],9]]]".parse::<Number>()?;
+        let (sum, log) = lhs.add_logged("[1,1]".parse()?, &rules);
+        assert_eq!(sum.to_string(), "[[[[0,7],4],[[7,8],[6,0]]],[8,1]]");
+        assert_eq!(
+            log.iter().map(|step| step.action).collect_vec(),
+            vec![
+                Action::Explode,
+                Action::Explode,
+                Action::Split,
+                Action::Split,
+                Action::Explode
+            ]
+        );
+        assert_eq!(
+            log[0].after.to_string(),
+            "[[[[0,7],4],[7,[[8,4],9]]],[1,1]]"
+        );
+        let magnitude = "[[[[8,7],[7,7]],[[8,6],[7,7]]],[[[0,7],[6,6]],[8,7]]]"
+            .parse::<Number>()?
+            .magnitude(&rules);
+        assert_eq!(magnitude, 3488);
+
+        Ok(())
+    }
+
+    #[test]
+    fn notation() -> Result<()> {
+        // Check numbers are written in the same notation they are read in, which is also how they
+        // serialize to JSON, and that invalid numbers report where they went wrong
+        let input = std::fs::read_to_string("res/day18")?;
+        for line in input.lines() {
+            let number = line.parse::<Number>()?;
+            assert_eq!(number.to_string(), line);
+            assert_eq!(serde_json::to_string(&number)?, line);
+            assert_eq!(serde_json::from_str::<Number>(line)?, number);
+        }
+        assert_eq!(
+            "[1,2".parse::<Number>(),
+            Err(ParseNumberError::Invalid { offset: 4 })
+        );
+        assert_eq!(
+            "[[1,x],2]".parse::<Number>(),
+            Err(ParseNumberError::Invalid { offset: 4 })
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn parse_depth() {
+        assert!("[[[[[1,2],3],4],5],6]".parse::<Number>().is_ok());
+        assert_eq!(
+            "[[[[[[1,2],3],4],5],6],7]".parse::<Number>(),
+            Err(ParseNumberError::TooDeep { offset: 5 })
+        );
+
+        // Check the limit is enforced while parsing, so nesting far too deep to recurse through
+        // is reported rather than overflowing the stack
+        let deep = "[".repeat(1_000_000) + "1";
+        assert_eq!(
+            deep.parse::<Number>(),
+            Err(ParseNumberError::TooDeep { offset: 5 })
+        );
+
+        // Check JSON follows the same limit as text
+        assert!(serde_json::from_str::<Number>("[[[[[1,2],3],4],5],6]").is_ok());
+        assert!(serde_json::from_str::<Number>("[[[[[[1,2],3],4],5],6],7]").is_err());
+    }
+
+    #[test]
+    fn custom_rules() -> Result<()> {
+        let rules = SnailfishRules::default();
+        let shallow = SnailfishRules::new(3, 11, (1, 1), 4)?;
+
+        let mut number = Number::parse("[[[[1,2],3],4],10]", &shallow)?;
+        number.reduce(&rules, None);
+        assert_eq!(number.to_string(), "[[[[1,2],3],4],[5,5]]");
+        number.reduce(&shallow, None);
+        assert_eq!(number.to_string(), "[[[0,5],4],[5,5]]");
+        let mut number = Number::parse("[11,10]", &shallow)?;
+        number.reduce(&shallow, None);
+        assert_eq!(number.to_string(), "[[5,6],10]");
+        assert_eq!(number.magnitude(&shallow), 21);
+        assert!(Number::parse("[[[[[1,2],3],4],5],6]", &shallow).is_err());
+
+        // Check rules under which reduction would never finish are rejected
+        for split_threshold in [0, 1] {
+            assert_eq!(
+                SnailfishRules::new(4, split_threshold, (3, 2), 5),
+                Err(RulesError::SplitThresholdTooLow { split_threshold })
+            );
+        }
+        let mut number = Number::parse("[2,[1,0]]", &SnailfishRules::new(4, 2, (3, 2), 5)?)?;
+        number.reduce(&SnailfishRules::new(4, 2, (3, 2), 5)?, None);
+        assert_eq!(number.to_string(), "[[1,1],[1,0]]");
+
+        Ok(())
+    }
+}
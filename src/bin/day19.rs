@@ -1,4 +1,8 @@
-use std::collections::{HashSet, VecDeque};
+use std::{
+    cmp::Ordering,
+    collections::{HashSet, VecDeque},
+    fmt,
+};
 
 use anyhow::{anyhow, Result};
 use euclid::default::Point3D;
@@ -11,6 +15,7 @@ use nom::{
     sequence::tuple,
     IResult,
 };
+use serde::Serialize;
 
 fn parse_number(input: &str) -> IResult<&str, i32> {
     map_res(recognize(tuple((opt(tag("-")), digit1))), str::parse::<i32>)(input)
@@ -25,72 +30,234 @@ fn parse_beacon(input: &str) -> IResult<&str, Point3D<i32>> {
 
 #[derive(Debug, Clone)]
 struct Scanner {
+    id: usize,
+    /// Where the scanner is. This is the origin until it is located.
+    position: Point3D<i32>,
     beacons: Vec<Point3D<i32>>,
+    /// For each beacon, the sorted squared distances to every other beacon. These don't depend
+    /// on the scanner's position or rotation, so can be compared between scanners.
+    fingerprints: Vec<Vec<i32>>,
+    /// The sorted squared distances between every pair of beacons.
+    distances: Vec<i32>,
+}
+
+impl Scanner {
+    fn new(id: usize, beacons: Vec<Point3D<i32>>) -> Self {
+        let fingerprints = beacons
+            .iter()
+            .map(|a| {
+                beacons
+                    .iter()
+                    .filter(|b| *b != a)
+                    .map(|b| (*b - *a).square_length())
+                    .sorted()
+                    .collect_vec()
+            })
+            .collect_vec();
+        let distances = beacons
+            .iter()
+            .tuple_combinations()
+            .map(|(a, b)| (*b - *a).square_length())
+            .sorted()
+            .collect_vec();
+
+        Scanner {
+            id,
+            position: Point3D::origin(),
+            beacons,
+            fingerprints,
+            distances,
+        }
+    }
+}
+
+/// Counts the values two sorted lists have in common, including repeats.
+fn shared_count(a: &[i32], b: &[i32]) -> usize {
+    let (mut i, mut j, mut count) = (0, 0, 0);
+    while i < a.len() && j < b.len() {
+        match a[i].cmp(&b[j]) {
+            Ordering::Less => i += 1,
+            Ordering::Greater => j += 1,
+            Ordering::Equal => {
+                count += 1;
+                i += 1;
+                j += 1;
+            }
+        }
+    }
+
+    count
 }
 
 fn parse_scanner(input: &str) -> IResult<&str, Scanner> {
     let (input, _) = tag("--- scanner ")(input)?;
-    let (input, _id) = map_res(digit1, str::parse::<usize>)(input)?;
+    let (input, id) = map_res(digit1, str::parse::<usize>)(input)?;
     let (input, _) = tag(" ---")(input)?;
     let (input, _) = newline(input)?;
 
     let (input, beacons) = separated_list1(newline, parse_beacon)(input)?;
 
-    Ok((input, Scanner { beacons }))
+    Ok((input, Scanner::new(id, beacons)))
 }
 
 fn parse_input(input: &str) -> IResult<&str, Vec<Scanner>> {
     separated_list1(multispace1, parse_scanner)(input)
 }
 
+type Transform = fn(&Point3D<i32>) -> Point3D<i32>;
+
+/// The 24 rotations a scanner may be facing in.
+const TRANSFORMS: [Transform; 24] = [
+    |p| Point3D::new(p.x, p.y, p.z),
+    |p| Point3D::new(p.x, p.z, -p.y),
+    |p| Point3D::new(p.x, -p.y, -p.z),
+    |p| Point3D::new(p.x, -p.z, p.y),
+    |p| Point3D::new(p.y, p.x, -p.z),
+    |p| Point3D::new(p.y, p.z, p.x),
+    |p| Point3D::new(p.y, -p.x, p.z),
+    |p| Point3D::new(p.y, -p.z, -p.x),
+    |p| Point3D::new(p.z, p.x, p.y),
+    |p| Point3D::new(p.z, p.y, -p.x),
+    |p| Point3D::new(p.z, -p.x, -p.y),
+    |p| Point3D::new(p.z, -p.y, p.x),
+    |p| Point3D::new(-p.x, p.y, -p.z),
+    |p| Point3D::new(-p.x, p.z, p.y),
+    |p| Point3D::new(-p.x, -p.y, p.z),
+    |p| Point3D::new(-p.x, -p.z, -p.y),
+    |p| Point3D::new(-p.y, p.x, p.z),
+    |p| Point3D::new(-p.y, p.z, -p.x),
+    |p| Point3D::new(-p.y, -p.x, -p.z),
+    |p| Point3D::new(-p.y, -p.z, p.x),
+    |p| Point3D::new(-p.z, p.x, -p.y),
+    |p| Point3D::new(-p.z, p.y, p.x),
+    |p| Point3D::new(-p.z, -p.x, p.y),
+    |p| Point3D::new(-p.z, -p.y, -p.x),
+];
+
+/// Returns the matrix for one of the rotations, by applying it to each axis in turn.
+fn rotation_matrix(transform: usize) -> [[i32; 3]; 3] {
+    let columns = [
+        Point3D::new(1, 0, 0),
+        Point3D::new(0, 1, 0),
+        Point3D::new(0, 0, 1),
+    ]
+    .map(|axis| TRANSFORMS[transform](&axis));
+
+    [
+        columns.map(|c| c.x),
+        columns.map(|c| c.y),
+        columns.map(|c| c.z),
+    ]
+}
+
+/// Where a scanner was found to be, relative to scanner 0.
+#[derive(Debug, Clone)]
+struct Placement {
+    position: Point3D<i32>,
+    /// The index into `TRANSFORMS` of the scanner's rotation.
+    transform: usize,
+    /// The ID of the already located scanner whose beacons this one's were matched with.
+    aligned_to: usize,
+    /// The scanner's beacons in absolute coordinates.
+    beacons: Vec<Point3D<i32>>,
+}
+
+/// How far a scanner can see along each axis.
+const SCANNER_RANGE: i32 = 1000;
+
+#[derive(Debug, Clone, Copy)]
+struct MatchOptions {
+    /// The number of beacons two scanners must have in common to be aligned.
+    min_overlap: usize,
+    /// The number of beacons in range of both scanners which may be seen by only one of them.
+    /// With exact data this is zero, so any such beacon means the alignment is wrong.
+    tolerance: usize,
+}
+
+impl Default for MatchOptions {
+    fn default() -> Self {
+        MatchOptions {
+            min_overlap: 12,
+            tolerance: 0,
+        }
+    }
+}
+
+fn in_range(scanner: Point3D<i32>, beacon: Point3D<i32>) -> bool {
+    let offset = beacon - scanner;
+    offset.x.abs() <= SCANNER_RANGE
+        && offset.y.abs() <= SCANNER_RANGE
+        && offset.z.abs() <= SCANNER_RANGE
+}
+
 fn locate_scanner(
     scanner: &Scanner,
     located_scanners: &[Scanner],
-) -> Option<(Point3D<i32>, Vec<Point3D<i32>>)> {
-    let transforms = [
-        |p: &Point3D<i32>| Point3D::new(p.x, p.y, p.z),
-        |p: &Point3D<i32>| Point3D::new(p.x, p.z, -p.y),
-        |p: &Point3D<i32>| Point3D::new(p.x, -p.y, -p.z),
-        |p: &Point3D<i32>| Point3D::new(p.x, -p.z, p.y),
-        |p: &Point3D<i32>| Point3D::new(p.y, p.x, -p.z),
-        |p: &Point3D<i32>| Point3D::new(p.y, p.z, p.x),
-        |p: &Point3D<i32>| Point3D::new(p.y, -p.x, p.z),
-        |p: &Point3D<i32>| Point3D::new(p.y, -p.z, -p.x),
-        |p: &Point3D<i32>| Point3D::new(p.z, p.x, p.y),
-        |p: &Point3D<i32>| Point3D::new(p.z, p.y, -p.x),
-        |p: &Point3D<i32>| Point3D::new(p.z, -p.x, -p.y),
-        |p: &Point3D<i32>| Point3D::new(p.z, -p.y, p.x),
-        |p: &Point3D<i32>| Point3D::new(-p.x, p.y, -p.z),
-        |p: &Point3D<i32>| Point3D::new(-p.x, p.z, p.y),
-        |p: &Point3D<i32>| Point3D::new(-p.x, -p.y, p.z),
-        |p: &Point3D<i32>| Point3D::new(-p.x, -p.z, -p.y),
-        |p: &Point3D<i32>| Point3D::new(-p.y, p.x, p.z),
-        |p: &Point3D<i32>| Point3D::new(-p.y, p.z, -p.x),
-        |p: &Point3D<i32>| Point3D::new(-p.y, -p.x, -p.z),
-        |p: &Point3D<i32>| Point3D::new(-p.y, -p.z, p.x),
-        |p: &Point3D<i32>| Point3D::new(-p.z, p.x, -p.y),
-        |p: &Point3D<i32>| Point3D::new(-p.z, p.y, p.x),
-        |p: &Point3D<i32>| Point3D::new(-p.z, -p.x, p.y),
-        |p: &Point3D<i32>| Point3D::new(-p.z, -p.y, -p.x),
-    ];
+    options: &MatchOptions,
+) -> Option<Placement> {
+    let min_overlap = options.min_overlap;
 
     for known_scanner in located_scanners {
-        for transform in &transforms {
+        // Overlapping beacons would share all the distances between them, so skip scanners which
+        // can't have enough in common.
+        let shared_distances = shared_count(&scanner.distances, &known_scanner.distances);
+        if shared_distances < min_overlap * min_overlap.saturating_sub(1) / 2 {
+            continue;
+        }
+
+        // Pairs of beacons which could be the same one, judging by their distances to the others
+        let candidates = scanner
+            .fingerprints
+            .iter()
+            .enumerate()
+            .cartesian_product(known_scanner.fingerprints.iter().enumerate())
+            .filter(|((_, a), (_, b))| shared_count(a, b) >= min_overlap.saturating_sub(1))
+            .map(|((a, _), (b, _))| (a, b))
+            .collect_vec();
+        if candidates.len() < min_overlap {
+            continue;
+        }
+
+        let known_beacons = known_scanner.beacons.iter().collect::<HashSet<_>>();
+
+        for (transform_index, transform) in TRANSFORMS.iter().enumerate() {
             let transformed_points = scanner.beacons.iter().map(transform).collect_vec();
 
-            for offset in transformed_points
+            for offset in candidates
                 .iter()
-                .cartesian_product(&known_scanner.beacons)
-                .map(|(a, b)| *a - *b)
+                .map(|&(a, b)| transformed_points[a] - known_scanner.beacons[b])
             {
                 let test_points = transformed_points.iter().map(|p| *p - offset).collect_vec();
                 let matching = test_points
                     .iter()
-                    .filter(|p| known_scanner.beacons.contains(p))
-                    .collect_vec();
+                    .filter(|p| known_beacons.contains(p))
+                    .count();
 
-                if matching.len() >= 12 {
-                    return Some((offset.to_point(), test_points));
+                if matching < min_overlap {
+                    continue;
+                }
+
+                // Each scanner should see every beacon the other one does within its range
+                let position = (-offset).to_point();
+                let test_beacons = test_points.iter().collect::<HashSet<_>>();
+                let spurious = test_points
+                    .iter()
+                    .filter(|p| in_range(known_scanner.position, **p) && !known_beacons.contains(p))
+                    .chain(
+                        known_scanner
+                            .beacons
+                            .iter()
+                            .filter(|p| in_range(position, **p) && !test_beacons.contains(p)),
+                    )
+                    .count();
+
+                if spurious <= options.tolerance {
+                    return Some(Placement {
+                        position,
+                        transform: transform_index,
+                        aligned_to: known_scanner.id,
+                        beacons: test_points,
+                    });
                 }
             }
         }
@@ -99,12 +266,39 @@ fn locate_scanner(
     None
 }
 
-fn main() -> Result<()> {
-    let input = std::fs::read_to_string("res/day19")?;
-    let scanners = parse_input(&input)
-        .map_err(|e| anyhow!("Error parsing input: {:?}", e))?
-        .1;
+/// A summary of where a scanner is and how it was located, for exporting.
+#[derive(Debug, Clone, Serialize)]
+struct ScannerReport {
+    id: usize,
+    position: [i32; 3],
+    /// The matrix which rotates the scanner's coordinates to absolute ones.
+    rotation: [[i32; 3]; 3],
+    /// The scanner whose beacons were used to locate this one. `None` for scanner 0, which
+    /// defines the absolute coordinates.
+    aligned_to: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct UnplacedScanners(Vec<usize>);
+
+impl fmt::Display for UnplacedScanners {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Couldn't place scanners {} relative to scanner 0",
+            self.0.iter().join(", ")
+        )
+    }
+}
+
+impl std::error::Error for UnplacedScanners {}
 
+/// Locates all of the scanners relative to the first one. The returned scanners' beacons are
+/// in absolute coordinates.
+fn align_scanners(
+    scanners: &[Scanner],
+    options: &MatchOptions,
+) -> Result<(Vec<Scanner>, Vec<ScannerReport>), UnplacedScanners> {
     let (first_scanner, scanners) = scanners.split_first().unwrap();
 
     // List of scanners whose locations we know. Beacon coordinates are absolute.
@@ -113,26 +307,186 @@ fn main() -> Result<()> {
     // List of scanners we still need to locate.
     let mut unlocated_scanners = scanners.iter().cloned().collect::<VecDeque<Scanner>>();
 
-    // Set of located beacons in absolute coordinates.
-    let mut known_beacons: HashSet<Point3D<i32>> = HashSet::new();
-    known_beacons.extend(first_scanner.beacons.iter());
+    // Number of scanners tried since one was last located. Once every remaining scanner has
+    // been tried, none of them will ever match.
+    let mut attempts = 0;
 
-    // Location of each scanner in absolute coordinates
-    let mut scanner_offsets = vec![Point3D::new(0, 0, 0)];
+    let mut reports = vec![ScannerReport {
+        id: first_scanner.id,
+        position: [0, 0, 0],
+        rotation: rotation_matrix(0),
+        aligned_to: None,
+    }];
 
     while let Some(mut scanner) = unlocated_scanners.pop_front() {
-        match locate_scanner(&scanner, &located_scanners) {
-            Some((scanner_offset, found_beacons)) => {
-                known_beacons.extend(found_beacons.clone());
-                scanner.beacons = found_beacons;
+        match locate_scanner(&scanner, &located_scanners, options) {
+            Some(placement) => {
+                reports.push(ScannerReport {
+                    id: scanner.id,
+                    position: placement.position.to_array(),
+                    rotation: rotation_matrix(placement.transform),
+                    aligned_to: Some(placement.aligned_to),
+                });
+                scanner.position = placement.position;
+                scanner.beacons = placement.beacons;
                 located_scanners.push(scanner);
-                scanner_offsets.push(scanner_offset);
+                attempts = 0;
             }
             None => {
                 // Couldn't find match. Try again after the remaining scanners.
                 unlocated_scanners.push_back(scanner);
+                attempts += 1;
+                if attempts >= unlocated_scanners.len() {
+                    return Err(UnplacedScanners(
+                        unlocated_scanners.iter().map(|s| s.id).sorted().collect(),
+                    ));
+                }
+            }
+        }
+    }
+
+    Ok((located_scanners, reports))
+}
+
+/// Formats the tree of which scanners were aligned against which in Graphviz's DOT language.
+fn alignment_graph(reports: &[ScannerReport]) -> String {
+    let mut graph = String::from("digraph alignment {\n");
+    for report in reports {
+        let [x, y, z] = report.position;
+        graph.push_str(&format!(
+            "    {} [label=\"scanner {}\\n{},{},{}\"];\n",
+            report.id, report.id, x, y, z
+        ));
+        if let Some(aligned_to) = report.aligned_to {
+            graph.push_str(&format!("    {} -> {};\n", aligned_to, report.id));
+        }
+    }
+    graph.push_str("}\n");
+
+    graph
+}
+
+#[derive(Debug, Clone, Copy)]
+enum MapFormat {
+    Ply,
+    Obj,
+    Csv,
+}
+
+/// Formats the located scanners and their beacons as a point cloud. Beacons seen by more than one
+/// scanner are only included once.
+fn export_map(located_scanners: &[Scanner], format: MapFormat) -> String {
+    let scanners = located_scanners
+        .iter()
+        .map(|s| (s.id, s.position))
+        .collect_vec();
+    let beacons = located_scanners
+        .iter()
+        .flat_map(|s| s.beacons.iter())
+        .unique()
+        .sorted_by_key(|p| p.to_tuple())
+        .collect_vec();
+
+    let mut map = String::new();
+    match format {
+        MapFormat::Ply => {
+            map.push_str("ply\nformat ascii 1.0\ncomment scanners are red, beacons are white\n");
+            map.push_str(&format!(
+                "element vertex {}\n",
+                scanners.len() + beacons.len()
+            ));
+            for property in ["float x", "float y", "float z"] {
+                map.push_str(&format!("property {}\n", property));
+            }
+            for property in ["uchar red", "uchar green", "uchar blue"] {
+                map.push_str(&format!("property {}\n", property));
+            }
+            map.push_str("end_header\n");
+            for (_, p) in &scanners {
+                map.push_str(&format!("{} {} {} 255 0 0\n", p.x, p.y, p.z));
+            }
+            for p in &beacons {
+                map.push_str(&format!("{} {} {} 255 255 255\n", p.x, p.y, p.z));
             }
         }
+        MapFormat::Obj => {
+            // Vertices are numbered from 1, scanners first, and each set is a separate object
+            for (_, p) in &scanners {
+                map.push_str(&format!("v {} {} {}\n", p.x, p.y, p.z));
+            }
+            for p in &beacons {
+                map.push_str(&format!("v {} {} {}\n", p.x, p.y, p.z));
+            }
+            map.push_str("o scanners\n");
+            map.push_str(&format!(
+                "p {}\n",
+                (1..=scanners.len()).map(|i| i.to_string()).join(" ")
+            ));
+            map.push_str("o beacons\n");
+            map.push_str(&format!(
+                "p {}\n",
+                (1..=beacons.len())
+                    .map(|i| (scanners.len() + i).to_string())
+                    .join(" ")
+            ));
+        }
+        MapFormat::Csv => {
+            map.push_str("kind,id,x,y,z\n");
+            for (id, p) in &scanners {
+                map.push_str(&format!("scanner,{},{},{},{}\n", id, p.x, p.y, p.z));
+            }
+            for p in &beacons {
+                map.push_str(&format!("beacon,,{},{},{}\n", p.x, p.y, p.z));
+            }
+        }
+    }
+
+    map
+}
+
+fn main() -> Result<()> {
+    let input = std::fs::read_to_string("res/day19")?;
+    let scanners = parse_input(&input)
+        .map_err(|e| anyhow!("Error parsing input: {:?}", e))?
+        .1;
+
+    let (located_scanners, reports) = align_scanners(&scanners, &MatchOptions::default())?;
+
+    // Set of located beacons in absolute coordinates.
+    let known_beacons = located_scanners
+        .iter()
+        .flat_map(|s| s.beacons.iter())
+        .collect::<HashSet<_>>();
+
+    // Location of each scanner in absolute coordinates
+    let scanner_offsets = located_scanners.iter().map(|s| s.position).collect_vec();
+
+    let args = std::env::args().skip(1).collect_vec();
+    match args.iter().map(String::as_str).collect_vec().as_slice() {
+        [] => {}
+        ["report", "json"] => {
+            println!("{}", serde_json::to_string_pretty(&reports)?);
+            return Ok(());
+        }
+        ["report", "dot"] => {
+            print!("{}", alignment_graph(&reports));
+            return Ok(());
+        }
+        ["export", format] => {
+            let format = match *format {
+                "ply" => MapFormat::Ply,
+                "obj" => MapFormat::Obj,
+                "csv" => MapFormat::Csv,
+                _ => return Err(anyhow!("Unknown map format: {}", format)),
+            };
+            print!("{}", export_map(&located_scanners, format));
+            return Ok(());
+        }
+        _ => {
+            return Err(anyhow!(
+                "Usage: day19 [report json | report dot | export ply | export obj | export csv]"
+            ))
+        }
     }
 
     let result_a = known_beacons.len();
@@ -154,3 +508,106 @@ fn main() -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn puzzle_scanners() -> Vec<Scanner> {
+        let input = std::fs::read_to_string("res/day19").unwrap();
+        parse_input(&input).unwrap().1
+    }
+
+    #[test]
+    fn unplaced_scanners() {
+        // Check that scanners which can't be placed are reported rather than retried forever
+        let scanners = puzzle_scanners();
+        let too_strict = MatchOptions {
+            min_overlap: 40,
+            ..MatchOptions::default()
+        };
+        assert_eq!(
+            align_scanners(&scanners, &too_strict).unwrap_err(),
+            UnplacedScanners(scanners[1..].iter().map(|s| s.id).collect())
+        );
+    }
+
+    #[test]
+    fn reports() {
+        // Check each report's rotation and position map the scanner's own coordinates on to the
+        // absolute ones
+        let scanners = puzzle_scanners();
+        let (located_scanners, reports) =
+            align_scanners(&scanners, &MatchOptions::default()).unwrap();
+        assert_eq!(reports.len(), scanners.len());
+        for report in &reports {
+            let raw = scanners.iter().find(|s| s.id == report.id).unwrap();
+            let aligned = located_scanners.iter().find(|s| s.id == report.id).unwrap();
+            assert_eq!(aligned.position.to_array(), report.position);
+            assert_eq!(raw.beacons.len(), aligned.beacons.len());
+            for (beacon, found) in raw.beacons.iter().zip(&aligned.beacons) {
+                let rotated = report
+                    .rotation
+                    .map(|row| row[0] * beacon.x + row[1] * beacon.y + row[2] * beacon.z);
+                assert_eq!(
+                    Point3D::from(rotated) + aligned.position.to_vector(),
+                    *found
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn tolerance() {
+        let scanners = puzzle_scanners();
+        let (located_scanners, reports) =
+            align_scanners(&scanners, &MatchOptions::default()).unwrap();
+
+        // Check that a false beacon seen by only one scanner is only tolerated when asked for.
+        // It's put halfway between the first two scanners located, so it's in range of both.
+        let second = &reports[1];
+        let false_beacon = located_scanners[1].position / 2;
+        assert!(!located_scanners
+            .iter()
+            .any(|s| s.beacons.contains(&false_beacon)));
+        let relative = false_beacon.to_array();
+        let relative = [0, 1, 2].map(|i| {
+            // The rotation's inverse is its transpose
+            (0..3)
+                .map(|j| second.rotation[j][i] * (relative[j] - second.position[j]))
+                .sum::<i32>()
+        });
+        let mut beacons = scanners
+            .iter()
+            .find(|s| s.id == second.id)
+            .unwrap()
+            .beacons
+            .clone();
+        beacons.push(Point3D::from(relative));
+        let noisy = Scanner::new(second.id, beacons);
+
+        assert!(locate_scanner(&noisy, &located_scanners[..1], &MatchOptions::default()).is_none());
+        let tolerant = MatchOptions {
+            tolerance: 1,
+            ..MatchOptions::default()
+        };
+        let placement = locate_scanner(&noisy, &located_scanners[..1], &tolerant).unwrap();
+        assert_eq!(placement.position, located_scanners[1].position);
+        assert_eq!(placement.beacons.last(), Some(&false_beacon));
+    }
+
+    #[test]
+    fn export() {
+        // Check the exported map has a line for each scanner and beacon
+        let scanners = puzzle_scanners();
+        let (located_scanners, _) = align_scanners(&scanners, &MatchOptions::default()).unwrap();
+        let num_beacons = located_scanners
+            .iter()
+            .flat_map(|s| s.beacons.iter())
+            .collect::<HashSet<_>>()
+            .len();
+
+        let csv = export_map(&located_scanners, MapFormat::Csv);
+        assert_eq!(csv.lines().count(), 1 + scanners.len() + num_beacons);
+    }
+}
@@ -7,85 +7,236 @@ use nom::{
     IResult,
 };
 
+use std::{fmt, path::Path, time::Instant};
+
 use anyhow::{anyhow, Result};
 use bitvec::{field::BitField, prelude as bv};
+use itertools::Itertools;
+
+#[derive(Debug, Clone, PartialEq)]
+enum AlgorithmError {
+    EvenKernel(usize),
+    KernelTooLarge(usize),
+    WrongLength {
+        kernel_size: usize,
+        expected: usize,
+        actual: usize,
+    },
+}
+
+impl fmt::Display for AlgorithmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AlgorithmError::EvenKernel(size) => {
+                write!(f, "Kernel size {} has no centre pixel", size)
+            }
+            AlgorithmError::KernelTooLarge(size) => {
+                write!(f, "Kernel size {} needs too long an algorithm", size)
+            }
+            AlgorithmError::WrongLength {
+                kernel_size,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "A {}x{} kernel needs an algorithm of {} bits, not {}",
+                kernel_size, kernel_size, expected, actual
+            ),
+        }
+    }
+}
+
+impl std::error::Error for AlgorithmError {}
 
+/// An image enhancement algorithm, giving the new value of a pixel for each possible square of
+/// pixels around it.
 #[derive(Debug, Clone)]
+struct Algorithm {
+    /// The width of the square of pixels, which is centred on the pixel
+    kernel_size: usize,
+    /// The new value for each square, indexed by its pixels read as a binary number, row by row
+    bits: bv::BitVec,
+}
+
+impl Algorithm {
+    fn new(bits: bv::BitVec, kernel_size: usize) -> Result<Self, AlgorithmError> {
+        if kernel_size.is_multiple_of(2) {
+            return Err(AlgorithmError::EvenKernel(kernel_size));
+        }
+        // The index has to fit in a usize
+        let pixels = kernel_size * kernel_size;
+        if pixels >= usize::BITS as usize {
+            return Err(AlgorithmError::KernelTooLarge(kernel_size));
+        }
+        let expected = 1 << pixels;
+        if bits.len() != expected {
+            return Err(AlgorithmError::WrongLength {
+                kernel_size,
+                expected,
+                actual: bits.len(),
+            });
+        }
+
+        Ok(Algorithm { kernel_size, bits })
+    }
+
+    /// How far the kernel reaches from its centre
+    fn radius(&self) -> usize {
+        self.kernel_size / 2
+    }
+}
+
+/// Ways of enhancing an image, which all give the same result.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Enhancer {
+    Direct,
+    Windowed,
+}
+
+#[derive(Debug, Clone, PartialEq)]
 struct Image {
     width: usize,
     height: usize,
     bits: bv::BitVec<bv::Msb0>,
+    /// The value of every pixel outside the image, which goes on forever
     extra_bits: bool,
 }
 
 impl Image {
-    fn pad(&mut self) {
-        let pad_top = self.bits[..self.width]
+    /// Grows the image by `amount` on each side whose outermost `amount` rows or columns aren't
+    /// all background, as those are the only sides where pixels beyond can change.
+    fn pad(&mut self, amount: usize) {
+        let amount_y = amount.min(self.height);
+        let amount_x = amount.min(self.width);
+        let pad_top = self.bits[..(amount_y * self.width)]
             .iter()
             .any(|b| *b != self.extra_bits);
-        let pad_bottom = self.bits[((self.height - 1) * self.width)..]
+        let pad_bottom = self.bits[((self.height - amount_y) * self.width)..]
             .iter()
             .any(|b| *b != self.extra_bits);
         let pad_left = self
             .bits
-            .iter()
-            .step_by(self.width)
-            .any(|b| *b != self.extra_bits);
-        let pad_right = self
-            .bits
-            .iter()
-            .skip(self.width - 1)
-            .step_by(self.width)
-            .any(|b| *b != self.extra_bits);
+            .chunks(self.width)
+            .any(|row| row[..amount_x].iter().any(|b| *b != self.extra_bits));
+        let pad_right = self.bits.chunks(self.width).any(|row| {
+            row[(self.width - amount_x)..]
+                .iter()
+                .any(|b| *b != self.extra_bits)
+        });
 
         let old_width = self.width;
-        self.width += pad_left as usize + pad_right as usize;
-        self.height += pad_top as usize + pad_bottom as usize;
+        self.width += (pad_left as usize + pad_right as usize) * amount;
+        self.height += (pad_top as usize + pad_bottom as usize) * amount;
 
         if pad_top || pad_bottom || pad_left || pad_right {
             let mut bits = bv::BitVec::new();
+            let background = std::iter::once(self.extra_bits).cycle();
             if pad_top {
-                bits.extend(std::iter::once(self.extra_bits).cycle().take(self.width));
+                bits.extend(background.clone().take(self.width * amount));
             }
             for row in self.bits.chunks(old_width) {
                 if pad_left {
-                    bits.push(self.extra_bits);
+                    bits.extend(background.clone().take(amount));
                 }
                 bits.extend(row);
                 if pad_right {
-                    bits.push(self.extra_bits);
+                    bits.extend(background.clone().take(amount));
                 }
             }
             if pad_bottom {
-                bits.extend(std::iter::once(self.extra_bits).cycle().take(self.width));
+                bits.extend(background.take(self.width * amount));
             }
             self.bits = bits;
         }
     }
 
-    fn enhance(&mut self, algorithm: &bv::BitVec) {
-        self.pad();
+    fn enhance(&mut self, algorithm: &Algorithm) {
+        self.enhance_with(algorithm, Enhancer::Windowed);
+    }
+
+    fn enhance_with(&mut self, algorithm: &Algorithm, enhancer: Enhancer) {
+        self.pad(algorithm.radius());
 
+        self.bits = match enhancer {
+            Enhancer::Direct => self.enhanced_direct(algorithm),
+            Enhancer::Windowed => self.enhanced_windowed(algorithm),
+        };
+
+        // Every pixel around a background pixel is background too, so the index is either all
+        // zeros or all ones
+        let extra_lookup = if self.extra_bits {
+            algorithm.bits.len() - 1
+        } else {
+            0
+        };
+        self.extra_bits = algorithm.bits[extra_lookup];
+    }
+
+    /// Enhances each pixel by reading all of the pixels around it.
+    fn enhanced_direct(&self, algorithm: &Algorithm) -> bv::BitVec<bv::Msb0> {
+        let radius = algorithm.radius() as isize;
         let width = self.width;
         let height = self.height;
 
-        let bits = (0..height)
+        (0..height)
             .flat_map(move |y| (0..width).map(move |x| (x, y)))
             .map(|(x, y)| {
-                let lookup = ((y as isize - 1)..=(y as isize + 1))
-                    .flat_map(move |y| ((x as isize - 1)..=(x as isize + 1)).map(move |x| (x, y)))
+                let lookup = ((y as isize - radius)..=(y as isize + radius))
+                    .flat_map(move |y| {
+                        ((x as isize - radius)..=(x as isize + radius)).map(move |x| (x, y))
+                    })
                     .map(|(x, y)| self.get_bit(x, y))
                     .collect::<bv::BitVec<bv::Msb0>>();
 
                 let lookup = lookup.load_be::<usize>();
-                algorithm[lookup]
+                algorithm.bits[lookup]
             })
-            .collect::<bv::BitVec<bv::Msb0>>();
+            .collect::<bv::BitVec<bv::Msb0>>()
+    }
+
+    /// Enhances each row of pixels by sliding the kernel along it. Each row of the kernel is kept
+    /// as a number, and moving right shifts in one new pixel per row.
+    fn enhanced_windowed(&self, algorithm: &Algorithm) -> bv::BitVec<bv::Msb0> {
+        let size = algorithm.kernel_size;
+        let radius = algorithm.radius() as isize;
+        let row_mask = (1 << size) - 1;
+        let background = self.extra_bits as usize;
 
-        self.bits = bits;
+        let mut bits = bv::BitVec::with_capacity(self.width * self.height);
+        let mut kernel_rows = vec![0; size];
+        for y in 0..(self.height as isize) {
+            // The rows under the kernel, or `None` for those outside the image
+            let rows = ((y - radius)..=(y + radius))
+                .map(|y| {
+                    (0..self.height as isize).contains(&y).then(|| {
+                        let start = y as usize * self.width;
+                        &self.bits[start..(start + self.width)]
+                    })
+                })
+                .collect_vec();
 
-        let extra_loookup = if self.extra_bits { (2 ^ 9) - 1 } else { 0 };
-        self.extra_bits = algorithm[extra_loookup];
+            for column in -radius..(self.width as isize + radius) {
+                for (kernel_row, row) in kernel_rows.iter_mut().zip(&rows) {
+                    let bit = match row {
+                        Some(row) if (0..self.width as isize).contains(&column) => {
+                            row[column as usize] as usize
+                        }
+                        _ => background,
+                    };
+                    *kernel_row = ((*kernel_row << 1) | bit) & row_mask;
+                }
+
+                // Once the kernel is full, it's centred on the pixel `radius` to the left
+                if column >= radius {
+                    let lookup = kernel_rows
+                        .iter()
+                        .fold(0, |lookup, kernel_row| (lookup << size) | kernel_row);
+                    bits.push(algorithm.bits[lookup]);
+                }
+            }
+        }
+
+        bits
     }
 
     fn get_bit(&self, x: isize, y: isize) -> bool {
@@ -95,18 +246,283 @@ impl Image {
             self.bits[x as usize + y as usize * self.width]
         }
     }
+}
+
+/// The file formats images can be loaded from and saved to. Lit pixels are white in every format,
+/// so an image looks the same whichever it's saved as. PBM files draw a 1 as black, so there lit
+/// pixels are written as 0.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    Text,
+    Pbm,
+    Pgm,
+    Png,
+}
+
+impl Format {
+    fn from_path(path: &str) -> Result<Self> {
+        match Path::new(path).extension().and_then(|e| e.to_str()) {
+            Some("txt") => Ok(Format::Text),
+            Some("pbm") => Ok(Format::Pbm),
+            Some("pgm") => Ok(Format::Pgm),
+            Some("png") => Ok(Format::Png),
+            _ => Err(anyhow!("Unknown image format for {}", path)),
+        }
+    }
+}
 
-    #[allow(dead_code)]
-    fn draw(&self) {
-        for i in 0..self.height {
-            let string = self.bits[(i * self.width)..((i + 1) * self.width)]
+/// Packs a row of pixels into bytes, first pixel in the highest bit, padding the last byte with 0s
+fn pack_row(row: &bv::BitSlice<bv::Msb0>) -> Vec<u8> {
+    row.chunks(8)
+        .map(|chunk| {
+            chunk
                 .iter()
-                .map(|b| if *b { '#' } else { '.' })
-                .collect::<String>();
-            println!("{}", &string);
+                .enumerate()
+                .fold(0, |byte, (i, bit)| byte | (*bit as u8) << (7 - i))
+        })
+        .collect()
+}
+
+/// Reads the next whitespace separated field of a PNM header, noting the background if a comment
+/// gives it.
+fn pnm_field<'a>(data: &'a [u8], pos: &mut usize, background: &mut bool) -> Result<&'a str> {
+    loop {
+        while data.get(*pos).is_some_and(u8::is_ascii_whitespace) {
+            *pos += 1;
+        }
+        if data.get(*pos) != Some(&b'#') {
+            break;
+        }
+        let end = data[*pos..]
+            .iter()
+            .position(|c| *c == b'\n')
+            .map_or(data.len(), |length| *pos + length);
+        let comment = std::str::from_utf8(&data[(*pos + 1)..end])?.trim();
+        if let Some(value) = comment.strip_prefix("background:") {
+            *background = value.trim() == "1";
+        }
+        *pos = end;
+    }
+
+    let start = *pos;
+    while data.get(*pos).is_some_and(|c| !c.is_ascii_whitespace()) {
+        *pos += 1;
+    }
+    if start == *pos {
+        return Err(anyhow!("Image header is truncated"));
+    }
+
+    Ok(std::str::from_utf8(&data[start..*pos])?)
+}
+
+impl Image {
+    fn load(path: &str) -> Result<Image> {
+        Image::decode(&std::fs::read(path)?, Format::from_path(path)?)
+    }
+
+    fn save(&self, path: &str) -> Result<()> {
+        std::fs::write(path, self.encode(Format::from_path(path)?)?)?;
+        Ok(())
+    }
+
+    fn decode(data: &[u8], format: Format) -> Result<Image> {
+        match format {
+            Format::Text => Image::from_text(std::str::from_utf8(data)?),
+            Format::Pbm | Format::Pgm => Image::from_pnm(data),
+            Format::Png => Image::from_png(data),
+        }
+    }
+
+    fn encode(&self, format: Format) -> Result<Vec<u8>> {
+        match format {
+            Format::Text => Ok(self.to_text().into_bytes()),
+            Format::Pbm | Format::Pgm => Ok(self.to_pnm(format)),
+            Format::Png => self.to_png(),
+        }
+    }
+
+    /// Formats the image as `#` and `.` pixels, after a line giving the background.
+    fn to_text(&self) -> String {
+        let pixel = |bit: bool| if bit { '#' } else { '.' };
+
+        let mut text = format!("background: {}\n", pixel(self.extra_bits));
+        for row in self.bits.chunks(self.width) {
+            text.extend(row.iter().map(|bit| pixel(*bit)));
+            text.push('\n');
         }
+
+        text
+    }
+
+    /// Reads an image written by `to_text`. Without the background line it's taken to be off.
+    fn from_text(text: &str) -> Result<Image> {
+        let (extra_bits, rows) = match text.strip_prefix("background: ") {
+            Some(rest) => {
+                let (rest, bit) =
+                    parse_bit(rest).map_err(|e| anyhow!("Error parsing background: {:?}", e))?;
+                (bit, rest.trim_start())
+            }
+            None => (false, text),
+        };
+        let mut image = parse_image(rows)
+            .map_err(|e| anyhow!("Error parsing image: {:?}", e))?
+            .1;
+        image.extra_bits = extra_bits;
+
+        Ok(image)
+    }
+
+    /// Writes a binary PBM or PGM file. The background is kept in a comment.
+    fn to_pnm(&self, format: Format) -> Vec<u8> {
+        let magic = if format == Format::Pbm { "P4" } else { "P5" };
+        let mut data = format!(
+            "{}\n# background: {}\n{} {}\n",
+            magic, self.extra_bits as u8, self.width, self.height
+        )
+        .into_bytes();
+
+        if format == Format::Pbm {
+            // Lit pixels are white, which PBM writes as 0
+            data.extend(
+                self.bits
+                    .chunks(self.width)
+                    .map(|row| !row.to_bitvec())
+                    .flat_map(|row| pack_row(&row)),
+            );
+        } else {
+            data.extend(b"255\n");
+            data.extend(self.bits.iter().map(|bit| if *bit { 255 } else { 0 }));
+        }
+
+        data
+    }
+
+    /// Reads a PBM or PGM file, in either their plain or binary forms.
+    fn from_pnm(data: &[u8]) -> Result<Image> {
+        let mut pos = 0;
+        let mut extra_bits = false;
+        let magic = pnm_field(data, &mut pos, &mut extra_bits)?;
+        let width = pnm_field(data, &mut pos, &mut extra_bits)?.parse::<usize>()?;
+        let height = pnm_field(data, &mut pos, &mut extra_bits)?.parse::<usize>()?;
+        let max = match magic {
+            "P2" | "P5" => pnm_field(data, &mut pos, &mut extra_bits)?.parse::<u16>()?,
+            _ => 1,
+        };
+        if width == 0 || height == 0 {
+            return Err(anyhow!("Image is empty: {}x{}", width, height));
+        }
+        // A single whitespace character separates the header from binary data
+        let binary = &data[(pos + 1).min(data.len())..];
+
+        let bits: bv::BitVec<bv::Msb0> = match magic {
+            "P1" => {
+                let mut bits = bv::BitVec::new();
+                let mut comment = false;
+                for c in &data[pos..] {
+                    match c {
+                        b'#' => comment = true,
+                        b'\n' => comment = false,
+                        b'0' | b'1' if !comment => bits.push(*c == b'0'),
+                        _ => {}
+                    }
+                }
+                bits
+            }
+            "P2" => (0..(width * height))
+                .map(|_| Ok(pnm_field(data, &mut pos, &mut extra_bits)?.parse::<u16>()? > max / 2))
+                .collect::<Result<_>>()?,
+            "P4" => binary
+                .chunks(width.div_ceil(8))
+                .take(height)
+                .flat_map(|row| {
+                    row.iter()
+                        .flat_map(|byte| (0..8).rev().map(move |i| (byte >> i) & 1 == 0))
+                        .take(width)
+                })
+                .collect(),
+            "P5" if max < 256 => binary.iter().map(|value| *value as u16 > max / 2).collect(),
+            "P5" => binary
+                .chunks_exact(2)
+                .map(|value| u16::from_be_bytes([value[0], value[1]]) > max / 2)
+                .collect(),
+            _ => return Err(anyhow!("Not a PBM or PGM image: {}", magic)),
+        };
+
+        if bits.len() < width * height {
+            return Err(anyhow!("Image data is truncated"));
+        }
+
+        Ok(Image {
+            width,
+            height,
+            bits: bits[..(width * height)].to_bitvec(),
+            extra_bits,
+        })
+    }
+
+    /// Writes a 1-bit greyscale PNG file. The background is kept in a text chunk.
+    fn to_png(&self) -> Result<Vec<u8>> {
+        let mut png = Vec::new();
+        let mut encoder = png::Encoder::new(&mut png, self.width as u32, self.height as u32);
+        encoder.set_color(png::ColorType::Grayscale);
+        encoder.set_depth(png::BitDepth::One);
+        let background = if self.extra_bits { "on" } else { "off" };
+        encoder.add_text_chunk("Background".to_string(), background.to_string())?;
+
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(
+            &self
+                .bits
+                .chunks(self.width)
+                .flat_map(pack_row)
+                .collect_vec(),
+        )?;
+        writer.finish()?;
+
+        Ok(png)
+    }
+
+    /// Reads a PNG file of any colour type and depth. Pixels are lit if their brightness is at
+    /// least half, ignoring any transparency.
+    fn from_png(data: &[u8]) -> Result<Image> {
+        let mut decoder = png::Decoder::new(data);
+        decoder.set_transformations(png::Transformations::normalize_to_color8());
+        let mut reader = decoder.read_info()?;
+        let extra_bits = reader
+            .info()
+            .uncompressed_latin1_text
+            .iter()
+            .any(|chunk| chunk.keyword == "Background" && chunk.text == "on");
+
+        let mut pixels = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut pixels)?;
+        let (width, height) = (info.width as usize, info.height as usize);
+
+        // After normalising, every sample is a byte, with the colour channels before any alpha
+        let samples = info.color_type.samples();
+        let channels = match info.color_type {
+            png::ColorType::GrayscaleAlpha | png::ColorType::Rgba => samples - 1,
+            _ => samples,
+        };
+        let bits = pixels
+            .chunks(info.line_size)
+            .take(height)
+            .flat_map(|line| line.chunks(samples).take(width))
+            .map(|pixel| {
+                let total = pixel[..channels].iter().map(|c| *c as usize).sum::<usize>();
+                total >= 128 * channels
+            })
+            .collect();
+
+        Ok(Image {
+            width,
+            height,
+            bits,
+            extra_bits,
+        })
     }
 }
+
 fn parse_bit(input: &str) -> IResult<&str, bool> {
     alt((map(tag("."), |_| false), map(tag("#"), |_| true)))(input)
 }
@@ -147,6 +563,49 @@ fn main() -> Result<()> {
     let (algorithm, image) = parse_input(&input)
         .map_err(|e| anyhow!("Error parsing input: {:?}", e))?
         .1;
+    let algorithm = Algorithm::new(algorithm, 3)?;
+
+    let args = std::env::args().skip(1).collect_vec();
+    match args.iter().map(String::as_str).collect_vec().as_slice() {
+        [] => {}
+        ["enhance", algorithm, passes, input, output] => {
+            let algorithm = parse_algorithm(&std::fs::read_to_string(algorithm)?)
+                .map_err(|e| anyhow!("Error parsing algorithm: {:?}", e))?
+                .1;
+            // Guess the kernel size from the length, leaving any mismatch to be reported below
+            let kernel_size = (1..8)
+                .step_by(2)
+                .find(|size| 1 << (size * size) == algorithm.len())
+                .unwrap_or(3);
+            let algorithm = Algorithm::new(algorithm, kernel_size)?;
+
+            let mut image = Image::load(input)?;
+            (0..passes.parse::<usize>()?).for_each(|_| image.enhance(&algorithm));
+            image.save(output)?;
+            return Ok(());
+        }
+        ["bench", passes] => {
+            let passes = passes.parse::<usize>()?;
+            for enhancer in [Enhancer::Direct, Enhancer::Windowed] {
+                let start = Instant::now();
+                let mut enhanced = image.clone();
+                (0..passes).for_each(|_| enhanced.enhance_with(&algorithm, enhancer));
+                println!(
+                    "{:?}: {} pixels on after {} passes in {:?}",
+                    enhancer,
+                    enhanced.bits.count_ones(),
+                    passes,
+                    start.elapsed()
+                );
+            }
+            return Ok(());
+        }
+        _ => {
+            return Err(anyhow!(
+                "Usage: day20 [enhance <algorithm> <passes> <input image> <output image> | bench <passes>]"
+            ))
+        }
+    }
 
     let mut image1 = image.clone();
     (0..2).for_each(|_| image1.enhance(&algorithm));
@@ -162,3 +621,138 @@ fn main() -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn puzzle() -> (Algorithm, Image) {
+        let input = std::fs::read_to_string("res/day20").unwrap();
+        let (algorithm, image) = parse_input(&input).unwrap().1;
+        (Algorithm::new(algorithm, 3).unwrap(), image)
+    }
+
+    #[test]
+    fn algorithm_length() {
+        // Check the algorithm has to match the kernel
+        let (algorithm, _) = puzzle();
+        assert_eq!(
+            Algorithm::new(algorithm.bits.clone(), 4).unwrap_err(),
+            AlgorithmError::EvenKernel(4)
+        );
+        assert_eq!(
+            Algorithm::new(algorithm.bits, 5).unwrap_err(),
+            AlgorithmError::WrongLength {
+                kernel_size: 5,
+                expected: 1 << 25,
+                actual: 512
+            }
+        );
+    }
+
+    #[test]
+    fn kernel_sizes() -> Result<()> {
+        // Check a 5x5 kernel which turns on any pixel near one that's on grows a single pixel
+        // into a square, and that a 1x1 kernel which inverts pixels flips the background too
+        let mut grow = bv::BitVec::repeat(true, 1 << 25);
+        grow.set(0, false);
+        let grow = Algorithm::new(grow, 5)?;
+        let mut dot = Image {
+            width: 1,
+            height: 1,
+            bits: std::iter::once(true).collect(),
+            extra_bits: false,
+        };
+        dot.enhance(&grow);
+        assert_eq!((dot.width, dot.height, dot.bits.count_ones()), (5, 5, 25));
+        dot.enhance(&grow);
+        assert_eq!((dot.width, dot.height, dot.bits.count_ones()), (9, 9, 81));
+        let invert = Algorithm::new([true, false].iter().collect(), 1)?;
+        dot.enhance(&invert);
+        assert_eq!((dot.bits.count_zeros(), dot.extra_bits), (81, true));
+
+        Ok(())
+    }
+
+    #[test]
+    fn enhancers_agree() -> Result<()> {
+        // Check the enhancers agree exactly, with 3x3 and 5x5 kernels and either background
+        let (algorithm, image) = puzzle();
+        let mut direct = image.clone();
+        let mut windowed = image;
+        for _ in 0..5 {
+            direct.enhance_with(&algorithm, Enhancer::Direct);
+            windowed.enhance_with(&algorithm, Enhancer::Windowed);
+            assert_eq!(direct, windowed);
+        }
+        assert!(direct.extra_bits);
+        // Built a word at a time, as collecting 2^25 bits one by one is slow without optimisation
+        let word_bits = usize::BITS as usize;
+        let blur = (0..(1 << 25) / word_bits)
+            .map(|word| {
+                let high = (word * word_bits).count_ones();
+                (0..word_bits).fold(0, |bits, i| {
+                    bits | (((high + i.count_ones() > 12) as usize) << i)
+                })
+            })
+            .collect_vec();
+        let blur = Algorithm::new(bv::BitVec::from_vec(blur), 5)?;
+        direct.enhance_with(&blur, Enhancer::Direct);
+        windowed.enhance_with(&blur, Enhancer::Windowed);
+        assert_eq!(direct, windowed);
+
+        Ok(())
+    }
+
+    #[test]
+    fn formats() -> Result<()> {
+        // Check images survive being saved and loaded in each format, including the background
+        let (algorithm, mut image) = puzzle();
+        image.enhance(&algorithm);
+        assert!(image.extra_bits);
+        for format in [Format::Text, Format::Pbm, Format::Pgm, Format::Png] {
+            assert_eq!(Image::decode(&image.encode(format)?, format)?, image);
+        }
+
+        // Check the plain forms of PBM and PGM, which draw lit pixels white like the others
+        let plain = Image::from_pnm(b"P1\n# background: 1\n3 2\n0 1 0\n101\n")?;
+        assert_eq!(
+            Image::from_pnm(b"P2\n# background: 1\n3 2 15\n15 0 15\n0 15 0\n")?,
+            plain
+        );
+        assert_eq!(plain.to_text(), "background: #\n#.#\n.#.\n");
+        assert_eq!(
+            Image::decode(&plain.encode(Format::Pbm)?, Format::Pbm)?,
+            plain
+        );
+
+        // Check empty images are rejected rather than panicking on the missing rows
+        for empty in [
+            &b"P4\n0 3\n"[..],
+            b"P4\n3 0\n",
+            b"P1\n0 0\n",
+            b"P5\n0 2 255\n",
+        ] {
+            assert!(Image::from_pnm(empty).is_err());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn png_colour() -> Result<()> {
+        // Check a compressed colour PNG with transparency is read by brightness
+        let mut data = Vec::new();
+        let mut encoder = png::Encoder::new(&mut data, 3, 1);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_compression(png::Compression::Best);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&[255, 255, 255, 0, 20, 40, 60, 255, 200, 100, 150, 255])?;
+        writer.finish()?;
+
+        let image = Image::from_png(&data)?;
+        assert_eq!(image.to_text(), "background: .\n#.#\n");
+
+        Ok(())
+    }
+}
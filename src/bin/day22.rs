@@ -1,6 +1,8 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, fmt, time::Instant};
 
 use anyhow::{anyhow, Result};
+use bitvec::prelude as bv;
+use itertools::Itertools;
 use nom::{
     branch::alt,
     bytes::complete::tag,
@@ -10,8 +12,17 @@ use nom::{
     sequence::{preceded, separated_pair, tuple},
     IResult,
 };
+use rand::{rngs::StdRng, Rng, SeedableRng};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy)]
+enum Axis {
+    X,
+    Y,
+    Z,
+}
+
+/// A block of cubes. The minimums are inclusive and the maximums exclusive.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 struct Cuboid {
     x_min: isize,
     x_max: isize,
@@ -22,93 +33,131 @@ struct Cuboid {
 }
 
 impl Cuboid {
+    fn is_empty(&self) -> bool {
+        self.x_min >= self.x_max || self.y_min >= self.y_max || self.z_min >= self.z_max
+    }
+
+    /// The number of cubes in the cuboid
+    fn volume(&self) -> u64 {
+        if self.is_empty() {
+            0
+        } else {
+            (self.x_max - self.x_min) as u64
+                * (self.y_max - self.y_min) as u64
+                * (self.z_max - self.z_min) as u64
+        }
+    }
+
     fn overlaps(&self, other: &Cuboid) -> bool {
-        self.x_min <= other.x_max
-            && other.x_min <= self.x_max
-            && self.y_min <= other.y_max
-            && other.y_min <= self.y_max
-            && self.z_min <= other.z_max
-            && other.z_min <= self.z_max
+        self.x_min < other.x_max
+            && other.x_min < self.x_max
+            && self.y_min < other.y_max
+            && other.y_min < self.y_max
+            && self.z_min < other.z_max
+            && other.z_min < self.z_max
     }
 
+    /// Whether every cube of other is also in self
     fn contains(&self, other: &Cuboid) -> bool {
-        let x_range = self.x_min..self.x_max;
-        let y_range = self.y_min..self.y_max;
-        let z_range = self.z_min..self.z_max;
+        other.is_empty()
+            || (self.x_min <= other.x_min
+                && other.x_max <= self.x_max
+                && self.y_min <= other.y_min
+                && other.y_max <= self.y_max
+                && self.z_min <= other.z_min
+                && other.z_max <= self.z_max)
+    }
 
-        x_range.contains(&other.x_min)
-            && x_range.contains(&other.x_max)
-            && y_range.contains(&other.y_min)
-            && y_range.contains(&other.y_max)
-            && z_range.contains(&other.z_min)
-            && z_range.contains(&other.z_max)
+    fn contains_point(&self, x: isize, y: isize, z: isize) -> bool {
+        (self.x_min..self.x_max).contains(&x)
+            && (self.y_min..self.y_max).contains(&y)
+            && (self.z_min..self.z_max).contains(&z)
     }
 
-    /// Subtract other from self and return the result as a list of cuboids
-    fn sub(&self, other: &Cuboid) -> Vec<Cuboid> {
-        let left = Cuboid {
-            x_min: self.x_min,
-            x_max: self.x_max.min(other.x_min),
-            y_min: self.y_min,
-            y_max: self.y_max,
-            z_min: self.z_min,
-            z_max: self.z_max,
-        };
-        let right = Cuboid {
-            x_min: self.x_min.max(other.x_max),
-            x_max: self.x_max,
-            y_min: self.y_min,
-            y_max: self.y_max,
-            z_min: self.z_min,
-            z_max: self.z_max,
-        };
-        let top = Cuboid {
-            x_min: self.x_min.max(other.x_min),
-            x_max: self.x_max.min(other.x_max),
-            y_min: self.y_min,
-            y_max: self.y_max.min(other.y_min),
-            z_min: self.z_min,
-            z_max: self.z_max,
-        };
-        let bottom = Cuboid {
-            x_min: self.x_min.max(other.x_min),
-            x_max: self.x_max.min(other.x_max),
-            y_min: self.y_min.max(other.y_max),
-            y_max: self.y_max,
-            z_min: self.z_min,
-            z_max: self.z_max,
-        };
-        let front = Cuboid {
-            x_min: self.x_min.max(other.x_min),
-            x_max: self.x_max.min(other.x_max),
-            y_min: self.y_min.max(other.y_min),
-            y_max: self.y_max.min(other.y_max),
-            z_min: self.z_min,
-            z_max: self.z_max.min(other.z_min),
-        };
-        let back = Cuboid {
+    /// The cubes in both self and other, if there are any
+    fn intersection(&self, other: &Cuboid) -> Option<Cuboid> {
+        let intersection = Cuboid {
             x_min: self.x_min.max(other.x_min),
             x_max: self.x_max.min(other.x_max),
             y_min: self.y_min.max(other.y_min),
             y_max: self.y_max.min(other.y_max),
-            z_min: self.z_min.max(other.z_max),
-            z_max: self.z_max,
+            z_min: self.z_min.max(other.z_min),
+            z_max: self.z_max.min(other.z_max),
         };
-        [left, right, top, bottom, front, back]
-            .iter()
-            .filter(|c| c.x_max - c.x_min > 0 && c.y_max - c.y_min > 0 && c.z_max - c.z_min > 0)
-            .cloned()
-            .collect::<Vec<Cuboid>>()
+
+        (!intersection.is_empty()).then_some(intersection)
+    }
+
+    /// Cuts self along a plane across the axis, returning the parts below and above `at`. Cubes
+    /// at `at` are in the part above.
+    fn split(&self, axis: Axis, at: isize) -> (Option<Cuboid>, Option<Cuboid>) {
+        let (mut below, mut above) = (self.clone(), self.clone());
+        match axis {
+            Axis::X => {
+                below.x_max = self.x_max.min(at);
+                above.x_min = self.x_min.max(at);
+            }
+            Axis::Y => {
+                below.y_max = self.y_max.min(at);
+                above.y_min = self.y_min.max(at);
+            }
+            Axis::Z => {
+                below.z_max = self.z_max.min(at);
+                above.z_min = self.z_min.max(at);
+            }
+        }
+
+        (
+            (!below.is_empty()).then_some(below),
+            (!above.is_empty()).then_some(above),
+        )
+    }
+
+    /// Subtract other from self and return the result as a list of cuboids
+    fn sub(&self, other: &Cuboid) -> Vec<Cuboid> {
+        let mut pieces = Vec::new();
+
+        // Cut away the parts either side of other along each axis in turn, leaving what's inside
+        let mut inside = self.clone();
+        for (axis, min, max) in [
+            (Axis::X, other.x_min, other.x_max),
+            (Axis::Y, other.y_min, other.y_max),
+            (Axis::Z, other.z_min, other.z_max),
+        ] {
+            let (below, rest) = inside.split(axis, min);
+            pieces.extend(below);
+            let (rest, above) = match rest {
+                Some(rest) => rest.split(axis, max),
+                None => return pieces,
+            };
+            pieces.extend(above);
+            inside = match rest {
+                Some(rest) => rest,
+                None => return pieces,
+            };
+        }
+
+        pieces
     }
 }
 
-#[derive(Debug)]
-struct Reactor {
+/// A way of keeping track of which cubes in the reactor are on.
+trait Reactor {
+    /// Sets a cuboid region either on or off
+    fn set_cuboid(&mut self, cuboid: &Cuboid, state: bool);
+
+    /// The number of cubes which are on
+    fn volume(&self) -> u64;
+}
+
+/// Keeps the cubes which are on as a list of disjoint cuboids, splitting them whenever a new
+/// cuboid overlaps them.
+#[derive(Debug, Default)]
+struct SplittingReactor {
     cuboids: Vec<Cuboid>,
 }
 
-impl Reactor {
-    /// Sets a cuboid region either on or off
+impl Reactor for SplittingReactor {
     fn set_cuboid(&mut self, cuboid: &Cuboid, state: bool) {
         let mut new_cuboid = Vec::new();
 
@@ -127,7 +176,7 @@ impl Reactor {
                 // retain the area of the existing cuboid that doesn't overlap with the new one
                 // if we are turning the new cuboid on we will do so below
                 let existing = existing_cuboid.sub(cuboid);
-                new_cuboid.extend(existing.into_iter());
+                new_cuboid.extend(existing);
             } else {
                 // no overlap, so ensure we retain the existing cuboid
                 new_cuboid.push(existing_cuboid.clone());
@@ -141,14 +190,132 @@ impl Reactor {
 
         self.cuboids = new_cuboid;
     }
+
+    fn volume(&self) -> u64 {
+        self.cuboids.iter().map(Cuboid::volume).sum()
+    }
 }
 
-#[derive(Debug)]
+/// Keeps the cubes which are on as cuboids which are counted a number of times, possibly
+/// negatively. Each step adds the intersection of its cuboid with every existing one, counted the
+/// opposite way to cancel them out, and then the cuboid itself if it's turning cubes on.
+#[derive(Debug, Default)]
+struct SignedReactor {
+    counts: HashMap<Cuboid, i64>,
+}
+
+impl SignedReactor {
+    /// Works out how the counts need to change to set a cuboid region on or off.
+    fn changes(&self, cuboid: &Cuboid, state: bool) -> HashMap<Cuboid, i64> {
+        let mut changes: HashMap<Cuboid, i64> = HashMap::new();
+        for (existing, count) in &self.counts {
+            if let Some(intersection) = existing.intersection(cuboid) {
+                *changes.entry(intersection).or_default() -= count;
+            }
+        }
+        if state {
+            *changes.entry(cuboid.clone()).or_default() += 1;
+        }
+
+        changes
+    }
+
+    /// Adds the changes to the counts, or takes them away again if `sign` is negative.
+    fn apply(&mut self, changes: &HashMap<Cuboid, i64>, sign: i64) {
+        for (changed, change) in changes {
+            let count = self.counts.entry(changed.clone()).or_default();
+            *count += sign * change;
+        }
+        // Cuboids which have cancelled out would only slow down later steps
+        self.counts.retain(|_, count| *count != 0);
+    }
+}
+
+impl Reactor for SignedReactor {
+    fn set_cuboid(&mut self, cuboid: &Cuboid, state: bool) {
+        let changes = self.changes(cuboid, state);
+        self.apply(&changes, 1);
+    }
+
+    fn volume(&self) -> u64 {
+        self.counts
+            .iter()
+            .map(|(cuboid, count)| cuboid.volume() as i128 * *count as i128)
+            .sum::<i128>() as u64
+    }
+}
+
+#[derive(Debug, Clone)]
 struct Step {
     cuboid: Cuboid,
     state: bool,
 }
 
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let c = &self.cuboid;
+        write!(
+            f,
+            "{} x={}..{},y={}..{},z={}..{}",
+            if self.state { "on" } else { "off" },
+            c.x_min,
+            c.x_max - 1,
+            c.y_min,
+            c.y_max - 1,
+            c.z_min,
+            c.z_max - 1
+        )
+    }
+}
+
+/// A reactor which remembers each reboot step, so earlier states can be looked at and steps can be
+/// undone.
+#[derive(Debug, Default)]
+struct RebootHistory {
+    reactor: SignedReactor,
+    steps: Vec<Step>,
+    /// How each step changed the reactor's counts, so it can be taken away again
+    changes: Vec<HashMap<Cuboid, i64>>,
+    /// The number of cubes on after each step
+    volumes: Vec<u64>,
+}
+
+impl RebootHistory {
+    fn push(&mut self, step: Step) {
+        let changes = self.reactor.changes(&step.cuboid, step.state);
+        self.reactor.apply(&changes, 1);
+        self.volumes.push(self.reactor.volume());
+        self.changes.push(changes);
+        self.steps.push(step);
+    }
+
+    /// Undoes the last `count` steps, returning them.
+    fn undo(&mut self, count: usize) -> Vec<Step> {
+        let keep = self.steps.len().saturating_sub(count);
+        for changes in self.changes.drain(keep..).rev() {
+            self.reactor.apply(&changes, -1);
+        }
+        self.volumes.truncate(keep);
+
+        self.steps.split_off(keep)
+    }
+
+    /// The number of cubes on after the first `count` steps, if there have been that many.
+    fn volume_after(&self, count: usize) -> Option<u64> {
+        match count {
+            0 => Some(0),
+            _ => self.volumes.get(count - 1).copied(),
+        }
+    }
+
+    /// The index of the last step whose cuboid includes the cube, if any did.
+    fn last_step_touching(&self, x: isize, y: isize, z: isize) -> Option<usize> {
+        self.steps
+            .iter()
+            .rposition(|step| step.cuboid.contains_point(x, y, z))
+    }
+}
+
 fn parse_number(input: &str) -> IResult<&str, isize> {
     map_res(
         recognize(tuple((opt(tag("-")), digit1))),
@@ -190,38 +357,191 @@ fn parse_input(input: &str) -> IResult<&str, Vec<Step>> {
     separated_list1(newline, parse_step)(input)
 }
 
-fn part_a(steps: &[Step]) -> usize {
-    let mut cuboids = HashMap::new();
+/// The state of the reactor after a series of reboot steps. Space is divided into a grid at every
+/// coordinate where any step's cuboid starts or ends, so each cell is either entirely on or off.
+#[derive(Debug)]
+struct VolumeGrid {
+    /// Where the cells start along each axis. Cell `i` covers `bounds[i]..bounds[i + 1]`.
+    x_bounds: Vec<isize>,
+    y_bounds: Vec<isize>,
+    z_bounds: Vec<isize>,
+    /// Whether each cell is on, indexed by x, then y, then z.
+    cells: bv::BitVec,
+}
+
+impl VolumeGrid {
+    fn new(steps: &[Step]) -> Self {
+        let bounds = |axis: fn(&Cuboid) -> [isize; 2]| {
+            steps
+                .iter()
+                .flat_map(|step| axis(&step.cuboid))
+                .sorted()
+                .dedup()
+                .collect_vec()
+        };
+        let x_bounds = bounds(|c| [c.x_min, c.x_max]);
+        let y_bounds = bounds(|c| [c.y_min, c.y_max]);
+        let z_bounds = bounds(|c| [c.z_min, c.z_max]);
 
-    for step in steps {
-        for pos in (step.cuboid.x_min.max(-50)..step.cuboid.x_max.min(50)).flat_map(move |x| {
-            (step.cuboid.y_min.max(-50)..step.cuboid.y_max.min(50)).flat_map(move |y| {
-                (step.cuboid.z_min.max(-50)..step.cuboid.z_max.min(50)).map(move |z| (x, y, z))
+        let (width, height, depth) = (
+            x_bounds.len().saturating_sub(1),
+            y_bounds.len().saturating_sub(1),
+            z_bounds.len().saturating_sub(1),
+        );
+        let mut cells = bv::BitVec::repeat(false, width * height * depth);
+
+        // Every cuboid lines up exactly with the cells, so the bounds can be found exactly
+        let cell_range = |bounds: &[isize], min, max| {
+            bounds.binary_search(&min).unwrap()..bounds.binary_search(&max).unwrap()
+        };
+        for step in steps {
+            let c = &step.cuboid;
+            let z_range = cell_range(&z_bounds, c.z_min, c.z_max);
+            for x in cell_range(&x_bounds, c.x_min, c.x_max) {
+                for y in cell_range(&y_bounds, c.y_min, c.y_max) {
+                    let row = (x * height + y) * depth;
+                    cells[(row + z_range.start)..(row + z_range.end)].set_all(step.state);
+                }
+            }
+        }
+
+        VolumeGrid {
+            x_bounds,
+            y_bounds,
+            z_bounds,
+            cells,
+        }
+    }
+
+    /// Returns the number of cubes inside `region` which are on.
+    fn count_on(&self, region: &Cuboid) -> u64 {
+        // The cells along an axis which are at least partly inside the region, and how much of
+        // each is inside
+        let overlaps = |bounds: &[isize], min: isize, max: isize| {
+            bounds
+                .iter()
+                .tuple_windows()
+                .enumerate()
+                .filter_map(move |(i, (&start, &end))| {
+                    let width = end.min(max) - start.max(min);
+                    (width > 0).then_some((i, width as u64))
+                })
+                .collect_vec()
+        };
+        let xs = overlaps(&self.x_bounds, region.x_min, region.x_max);
+        let ys = overlaps(&self.y_bounds, region.y_min, region.y_max);
+        let zs = overlaps(&self.z_bounds, region.z_min, region.z_max);
+        let (z_start, z_end) = match (zs.first(), zs.last()) {
+            (Some(first), Some(last)) => (first.0, last.0 + 1),
+            _ => return 0,
+        };
+        // Total width of the cells before each one, so runs of cells can be summed at once
+        let z_offsets = std::iter::once(0)
+            .chain(zs.iter().scan(0, |total, (_, width)| {
+                *total += width;
+                Some(*total)
+            }))
+            .collect_vec();
+
+        let height = self.y_bounds.len() - 1;
+        let depth = self.z_bounds.len() - 1;
+
+        xs.iter()
+            .cartesian_product(&ys)
+            .map(|(&(x, x_width), &(y, y_width))| {
+                let row = (x * height + y) * depth;
+                let cells = &self.cells[(row + z_start)..(row + z_end)];
+
+                let mut depth_on = 0;
+                let mut z = 0;
+                while let Some(start) = cells[z..].first_one() {
+                    let start = z + start;
+                    let end = cells[start..]
+                        .first_zero()
+                        .map_or(cells.len(), |length| start + length);
+                    depth_on += z_offsets[end] - z_offsets[start];
+                    z = end;
+                }
+
+                x_width * y_width * depth_on
             })
-        }) {
-            cuboids.insert(pos, step.state);
+            .sum()
+    }
+
+    /// Returns the total number of cubes which are on.
+    fn volume(&self) -> u64 {
+        match (
+            self.x_bounds.first().zip(self.x_bounds.last()),
+            self.y_bounds.first().zip(self.y_bounds.last()),
+            self.z_bounds.first().zip(self.z_bounds.last()),
+        ) {
+            (Some((&x_min, &x_max)), Some((&y_min, &y_max)), Some((&z_min, &z_max))) => self
+                .count_on(&Cuboid {
+                    x_min,
+                    x_max,
+                    y_min,
+                    y_max,
+                    z_min,
+                    z_max,
+                }),
+            _ => 0,
         }
     }
+}
+
+fn part_a(grid: &VolumeGrid) -> u64 {
+    grid.count_on(&Cuboid {
+        x_min: -50,
+        x_max: 51,
+        y_min: -50,
+        y_max: 51,
+        z_min: -50,
+        z_max: 51,
+    })
+}
 
-    cuboids.values().filter(|state| **state).count()
+fn part_b(grid: &VolumeGrid) -> u64 {
+    grid.volume()
 }
 
-fn part_b(steps: &[Step]) -> u64 {
-    let mut reactor = Reactor {
-        cuboids: Vec::new(),
-    };
+/// Finds the number of cubes which are on after every step, using the given reactor.
+fn reactor_volume<R: Reactor + Default>(steps: &[Step]) -> u64 {
+    let mut reactor = R::default();
 
     for step in steps {
         reactor.set_cuboid(&step.cuboid, step.state);
     }
 
-    reactor
-        .cuboids
-        .iter()
-        .map(|c| {
-            (c.x_max - c.x_min) as u64 * (c.y_max - c.y_min) as u64 * (c.z_max - c.z_min) as u64
-        })
-        .sum()
+    reactor.volume()
+}
+
+fn reboot_history(steps: &[Step]) -> RebootHistory {
+    let mut history = RebootHistory::default();
+    for step in steps {
+        history.push(step.clone());
+    }
+
+    history
+}
+
+/// Times each way of rebooting the reactor, checking they agree.
+fn benchmark(steps: &[Step]) {
+    let start = Instant::now();
+    let splitting = reactor_volume::<SplittingReactor>(steps);
+    let splitting_time = start.elapsed();
+
+    let start = Instant::now();
+    let signed = reactor_volume::<SignedReactor>(steps);
+    let signed_time = start.elapsed();
+
+    assert_eq!(splitting, signed);
+    println!(
+        "{} steps: {} cubes on. Splitting: {:?}, signed: {:?}",
+        steps.len(),
+        signed,
+        splitting_time,
+        signed_time
+    );
 }
 
 fn main() -> Result<()> {
@@ -230,13 +550,237 @@ fn main() -> Result<()> {
         .map_err(|e| anyhow!("Error parsing input: {:?}", e))?
         .1;
 
-    let result_a = part_a(&steps);
+    let args = std::env::args().skip(1).collect_vec();
+    match args.iter().map(String::as_str).collect_vec().as_slice() {
+        [] => {}
+        ["bench", count] => {
+            let count = count.parse::<usize>()?;
+            benchmark(&steps);
+            // Seeded, so each run uses the same steps
+            let mut rng = StdRng::seed_from_u64(22);
+            let random_steps = (0..count)
+                .map(|_| {
+                    let mut range = || {
+                        let min = rng.gen_range(-100_000..100_000);
+                        (min, min + rng.gen_range(1..=50_000))
+                    };
+                    let ((x_min, x_max), (y_min, y_max), (z_min, z_max)) =
+                        (range(), range(), range());
+                    Step {
+                        cuboid: Cuboid {
+                            x_min,
+                            x_max,
+                            y_min,
+                            y_max,
+                            z_min,
+                            z_max,
+                        },
+                        state: rng.gen(),
+                    }
+                })
+                .collect_vec();
+            benchmark(&random_steps);
+            return Ok(());
+        }
+        ["after", count] => {
+            let count = count.parse::<usize>()?;
+            let history = reboot_history(&steps);
+            let volume = history
+                .volume_after(count)
+                .ok_or_else(|| anyhow!("There are only {} steps", steps.len()))?;
+            println!("{} cubes on after {} steps", volume, count);
+            return Ok(());
+        }
+        ["undo", count] => {
+            let count = count.parse::<usize>()?;
+            let mut history = reboot_history(&steps);
+            let undone = history.undo(count);
+            println!(
+                "{} cubes on after undoing {} steps",
+                history.reactor.volume(),
+                undone.len()
+            );
+            return Ok(());
+        }
+        ["touched", x, y, z] => {
+            let (x, y, z) = (x.parse()?, y.parse()?, z.parse()?);
+            match reboot_history(&steps).last_step_touching(x, y, z) {
+                Some(index) => println!("Step {}: {}", index + 1, steps[index]),
+                None => println!("No step touched {},{},{}", x, y, z),
+            }
+            return Ok(());
+        }
+        _ => {
+            return Err(anyhow!(
+                "Usage: day22 [bench <random steps> | after <steps> | undo <steps> | touched <x> <y> <z>]"
+            ))
+        }
+    }
+
+    let grid = VolumeGrid::new(&steps);
+
+    let result_a = part_a(&grid);
     assert_eq!(result_a, 583636);
     println!("Day 22, part A: {}", result_a);
 
-    let result_b = part_b(&steps);
+    let result_b = part_b(&grid);
     assert_eq!(result_b, 1294137045134837);
     println!("Day 22, part B: {}", result_b);
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::{collection::vec, prelude::*};
+
+    /// The number of cubes in any of the cuboids
+    fn union_volume(cuboids: &[Cuboid]) -> u64 {
+        let mut reactor = SplittingReactor::default();
+        for cuboid in cuboids {
+            reactor.set_cuboid(cuboid, true);
+        }
+
+        reactor.volume()
+    }
+
+    /// Every cube which small cuboids may cover
+    fn voxels() -> impl Iterator<Item = (isize, isize, isize)> {
+        (-4..8).flat_map(|x| (-4..8).flat_map(move |y| (-4..8).map(move |z| (x, y, z))))
+    }
+
+    /// Counts the cubes for which `inside` is true
+    fn count_voxels(inside: impl Fn(isize, isize, isize) -> bool) -> u64 {
+        voxels().filter(|&(x, y, z)| inside(x, y, z)).count() as u64
+    }
+
+    /// A cuboid starting within 4 of the origin, and at most 4 across.
+    fn cuboid() -> impl Strategy<Value = Cuboid> {
+        let range = || (-4..4isize, 1..=4isize).prop_map(|(min, size)| (min, min + size));
+        (range(), range(), range()).prop_map(|((x_min, x_max), (y_min, y_max), (z_min, z_max))| {
+            Cuboid {
+                x_min,
+                x_max,
+                y_min,
+                y_max,
+                z_min,
+                z_max,
+            }
+        })
+    }
+
+    /// A step which is more often on than off, so that reboots leave some cubes on.
+    fn step() -> impl Strategy<Value = Step> {
+        (cuboid(), prop::bool::weighted(0.67)).prop_map(|(cuboid, state)| Step { cuboid, state })
+    }
+
+    fn point() -> impl Strategy<Value = (isize, isize, isize)> {
+        (-4..8isize, -4..8isize, -4..8isize)
+    }
+
+    proptest! {
+        #[test]
+        fn volume(a in cuboid()) {
+            prop_assert_eq!(a.volume(), count_voxels(|x, y, z| a.contains_point(x, y, z)));
+        }
+
+        #[test]
+        fn intersection(a in cuboid(), b in cuboid()) {
+            let both =
+                count_voxels(|x, y, z| a.contains_point(x, y, z) && b.contains_point(x, y, z));
+            let intersection = a.intersection(&b);
+            prop_assert_eq!(intersection.as_ref().map_or(0, Cuboid::volume), both);
+            prop_assert_eq!(a.overlaps(&b), both > 0);
+            if let Some(intersection) = &intersection {
+                prop_assert!(a.contains(intersection) && b.contains(intersection));
+            }
+        }
+
+        #[test]
+        fn containment(a in cuboid(), b in cuboid()) {
+            let b_in_a =
+                voxels().all(|(x, y, z)| !b.contains_point(x, y, z) || a.contains_point(x, y, z));
+            prop_assert_eq!(a.contains(&b), b_in_a);
+            prop_assert!(a.contains(&a));
+        }
+
+        #[test]
+        fn difference(a in cuboid(), b in cuboid()) {
+            let difference = a.sub(&b);
+            prop_assert_eq!(
+                difference.iter().map(Cuboid::volume).sum::<u64>(),
+                count_voxels(|x, y, z| a.contains_point(x, y, z) && !b.contains_point(x, y, z))
+            );
+            for (i, piece) in difference.iter().enumerate() {
+                prop_assert!(a.contains(piece) && !piece.overlaps(&b));
+                prop_assert!(difference[i + 1..].iter().all(|other| !piece.overlaps(other)));
+            }
+        }
+
+        #[test]
+        fn split(
+            a in cuboid(),
+            axis in prop_oneof![Just(Axis::X), Just(Axis::Y), Just(Axis::Z)],
+            at in -5..9isize,
+        ) {
+            let (below, above) = a.split(axis, at);
+            let pieces = below.iter().chain(&above).collect_vec();
+            prop_assert_eq!(pieces.iter().map(|c| c.volume()).sum::<u64>(), a.volume());
+            prop_assert!(pieces.iter().all(|piece| a.contains(piece)));
+            if let (Some(below), Some(above)) = (&below, &above) {
+                prop_assert!(!below.overlaps(above));
+            }
+        }
+
+        #[test]
+        fn union(cuboids in vec(cuboid(), 1..6)) {
+            prop_assert_eq!(
+                union_volume(&cuboids),
+                count_voxels(|x, y, z| cuboids.iter().any(|c| c.contains_point(x, y, z)))
+            );
+        }
+
+        // Whole reboots, including regions which only partly overlap the grid's cells
+        #[test]
+        fn reboot(
+            steps in vec(step(), 1..8),
+            (x, y, z) in point(),
+            undo in 0..10usize,
+            region in cuboid(),
+        ) {
+            let is_on = |x, y, z| {
+                steps
+                    .iter()
+                    .rev()
+                    .find(|step| step.cuboid.contains_point(x, y, z))
+                    .is_some_and(|step| step.state)
+            };
+
+            let grid = VolumeGrid::new(&steps);
+            prop_assert_eq!(grid.volume(), count_voxels(is_on));
+            prop_assert_eq!(reactor_volume::<SplittingReactor>(&steps), count_voxels(is_on));
+            prop_assert_eq!(reactor_volume::<SignedReactor>(&steps), count_voxels(is_on));
+            prop_assert_eq!(
+                grid.count_on(&region),
+                count_voxels(|x, y, z| region.contains_point(x, y, z) && is_on(x, y, z))
+            );
+
+            // Check the history against rebooting with fewer steps
+            let mut history = reboot_history(&steps);
+            for count in 0..=steps.len() {
+                prop_assert_eq!(
+                    history.volume_after(count),
+                    Some(reactor_volume::<SplittingReactor>(&steps[..count]))
+                );
+            }
+            match history.last_step_touching(x, y, z) {
+                Some(last) => prop_assert_eq!(steps[last].state, is_on(x, y, z)),
+                None => prop_assert!(!is_on(x, y, z)),
+            }
+            history.undo(undo);
+            let kept = &steps[..steps.len().saturating_sub(undo)];
+            prop_assert_eq!(history.reactor.volume(), reactor_volume::<SplittingReactor>(kept));
+        }
+    }
+}
@@ -1,5 +1,6 @@
 use std::{
     collections::HashMap,
+    fmt,
     ops::{Index, IndexMut},
 };
 
@@ -8,14 +9,14 @@ use itertools::Itertools;
 use nom::{
     branch::alt,
     bytes::complete::tag,
-    character::complete::{digit1, newline},
-    combinator::{map, map_res, opt, recognize, value},
+    character::complete::{digit1, newline, not_line_ending, space0},
+    combinator::{all_consuming, map, map_res, opt, recognize, value},
     multi::separated_list1,
-    sequence::{preceded, separated_pair, tuple},
-    IResult,
+    sequence::{delimited, preceded, separated_pair, tuple},
+    Finish, IResult,
 };
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 enum Variable {
     X,
     Y,
@@ -23,37 +24,60 @@ enum Variable {
     W,
 }
 
-impl Index<&Variable> for [i64; 4] {
-    type Output = i64;
+impl Variable {
+    /// The index of the variable's register in the ALU's register file.
+    fn register(&self) -> usize {
+        match self {
+            Variable::W => 0,
+            Variable::X => 1,
+            Variable::Y => 2,
+            Variable::Z => 3,
+        }
+    }
+}
+
+impl<T> Index<&Variable> for [T; 4] {
+    type Output = T;
 
     fn index(&self, index: &Variable) -> &Self::Output {
-        match index {
-            Variable::W => &self[0],
-            Variable::X => &self[1],
-            Variable::Y => &self[2],
-            Variable::Z => &self[3],
-        }
+        &self[index.register()]
     }
 }
 
-impl IndexMut<&Variable> for [i64; 4] {
+impl<T> IndexMut<&Variable> for [T; 4] {
     fn index_mut(&mut self, index: &Variable) -> &mut Self::Output {
-        match index {
-            Variable::W => &mut self[0],
-            Variable::X => &mut self[1],
-            Variable::Y => &mut self[2],
-            Variable::Z => &mut self[3],
-        }
+        &mut self[index.register()]
     }
 }
 
-#[derive(Debug, Clone)]
+impl fmt::Display for Variable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Variable::W => "w",
+            Variable::X => "x",
+            Variable::Y => "y",
+            Variable::Z => "z",
+        };
+        f.pad(name)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 enum Operand {
     Variable(Variable),
     Literal(i64),
 }
 
-#[derive(Debug, Clone)]
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Operand::Variable(var) => write!(f, "{}", var),
+            Operand::Literal(l) => write!(f, "{}", l),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 enum Instruction {
     Inp(Variable),
     Add(Variable, Operand),
@@ -63,6 +87,22 @@ enum Instruction {
     Eql(Variable, Operand),
 }
 
+/// Formats the instruction in the same syntax it is parsed from, so a program can be written back
+/// out as text.
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let text = match self {
+            Instruction::Inp(var) => format!("inp {}", var),
+            Instruction::Add(var, op) => format!("add {} {}", var, op),
+            Instruction::Mul(var, op) => format!("mul {} {}", var, op),
+            Instruction::Div(var, op) => format!("div {} {}", var, op),
+            Instruction::Mod(var, op) => format!("mod {} {}", var, op),
+            Instruction::Eql(var, op) => format!("eql {} {}", var, op),
+        };
+        f.pad(&text)
+    }
+}
+
 fn parse_var(input: &str) -> IResult<&str, Variable> {
     alt((
         value(Variable::W, tag("w")),
@@ -126,8 +166,102 @@ fn parse_instruction(input: &str) -> IResult<&str, Instruction> {
     ))(input)
 }
 
+/// Parses a line containing an instruction, a comment starting with `;`, both, or nothing.
+fn parse_line(input: &str) -> IResult<&str, Option<Instruction>> {
+    delimited(
+        space0,
+        opt(parse_instruction),
+        tuple((space0, opt(preceded(tag(";"), not_line_ending)))),
+    )(input)
+}
+
+/// Parses a whole program. Anything which isn't an instruction or a comment is an error.
 fn parse_input(input: &str) -> IResult<&str, Vec<Instruction>> {
-    separated_list1(newline, parse_instruction)(input)
+    all_consuming(map(separated_list1(newline, parse_line), |lines| {
+        lines.into_iter().flatten().collect()
+    }))(input)
+}
+
+/// An error raised by the ALU when the program does something the puzzle declares invalid.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum AluError {
+    DivisionByZero { pc: usize },
+    NegativeModulo { pc: usize },
+    InputUnderflow { pc: usize },
+}
+
+impl fmt::Display for AluError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AluError::DivisionByZero { pc } => write!(f, "Division by zero at instruction {}", pc),
+            AluError::NegativeModulo { pc } => {
+                write!(f, "Modulo with negative operand at instruction {}", pc)
+            }
+            AluError::InputUnderflow { pc } => {
+                write!(f, "Ran out of input at instruction {}", pc)
+            }
+        }
+    }
+}
+
+impl std::error::Error for AluError {}
+
+fn divide(lhs: i64, rhs: i64, pc: usize) -> Result<i64, AluError> {
+    if rhs == 0 {
+        return Err(AluError::DivisionByZero { pc });
+    }
+    Ok(lhs / rhs)
+}
+
+fn modulo(lhs: i64, rhs: i64, pc: usize) -> Result<i64, AluError> {
+    if rhs == 0 {
+        return Err(AluError::DivisionByZero { pc });
+    }
+    if lhs < 0 || rhs < 0 {
+        return Err(AluError::NegativeModulo { pc });
+    }
+    Ok(lhs % rhs)
+}
+
+#[derive(Debug, Clone)]
+enum Breakpoint {
+    /// Stops before executing the instruction at the given index.
+    Instruction(usize),
+    /// Stops when an instruction sets the register to the given value, having previously held a
+    /// different one.
+    Register(Variable, i64),
+}
+
+/// The reason execution stopped when resuming the ALU.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Stop {
+    Halted,
+    /// Stopped before the instruction at the given index. A register breakpoint set by the last
+    /// instruction stops at the program's length.
+    Breakpoint(usize),
+}
+
+/// A record of a single executed instruction and the register values after it ran.
+#[derive(Debug, Clone)]
+struct TraceEntry {
+    pc: usize,
+    instruction: Instruction,
+    variables: [i64; 4],
+}
+
+impl fmt::Display for TraceEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:4}: {:<12} w={} x={} y={} z={}",
+            self.pc,
+            self.instruction,
+            self.variables[&Variable::W],
+            self.variables[&Variable::X],
+            self.variables[&Variable::Y],
+            self.variables[&Variable::Z]
+        )
+    }
 }
 
 #[derive(Debug)]
@@ -135,6 +269,11 @@ struct Alu {
     program: Vec<Instruction>,
     input: Vec<u64>,
     variables: [i64; 4],
+    pc: usize,
+    breakpoints: Vec<Breakpoint>,
+    trace: Option<Vec<TraceEntry>>,
+    /// Whether execution has been resumed, so a breakpoint on instruction 0 is only hit once
+    started: bool,
 }
 
 impl Alu {
@@ -146,65 +285,807 @@ impl Alu {
             program: program.to_vec(),
             input: input.iter().rev().cloned().collect::<Vec<_>>(),
             variables,
+            pc: 0,
+            breakpoints: Vec::new(),
+            trace: None,
+            started: false,
+        }
+    }
+
+    fn add_breakpoint(&mut self, breakpoint: Breakpoint) {
+        self.breakpoints.push(breakpoint);
+    }
+
+    /// Starts recording a trace entry for every instruction executed from now on.
+    fn enable_trace(&mut self) {
+        self.trace.get_or_insert_with(Vec::new);
+    }
+
+    /// Returns and clears the trace entries recorded so far.
+    fn take_trace(&mut self) -> Vec<TraceEntry> {
+        self.trace.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    fn operand(&self, operand: &Operand) -> i64 {
+        match operand {
+            Operand::Literal(l) => *l,
+            Operand::Variable(v) => self.variables[v],
+        }
+    }
+
+    /// Executes the next instruction. Returns false if the program has already completed.
+    /// On error the registers and program counter are left untouched.
+    fn step(&mut self) -> Result<bool, AluError> {
+        let pc = self.pc;
+        let instr = match self.program.get(pc) {
+            Some(instr) => instr,
+            None => return Ok(false),
+        };
+
+        match instr {
+            Instruction::Inp(var) => {
+                let val = self.input.pop().ok_or(AluError::InputUnderflow { pc })?;
+                self.variables[var] = val as i64;
+            }
+            Instruction::Add(lhs, rhs) => {
+                self.variables[lhs] += self.operand(rhs);
+            }
+            Instruction::Mul(lhs, rhs) => {
+                self.variables[lhs] *= self.operand(rhs);
+            }
+            Instruction::Div(lhs, rhs) => {
+                self.variables[lhs] = divide(self.variables[lhs], self.operand(rhs), pc)?;
+            }
+            Instruction::Mod(lhs, rhs) => {
+                self.variables[lhs] = modulo(self.variables[lhs], self.operand(rhs), pc)?;
+            }
+            Instruction::Eql(lhs, rhs) => {
+                let rhs = self.operand(rhs);
+                self.variables[lhs] = (self.variables[lhs] == rhs) as i64;
+            }
+        }
+
+        if let Some(trace) = &mut self.trace {
+            trace.push(TraceEntry {
+                pc,
+                instruction: instr.clone(),
+                variables: self.variables,
+            });
         }
+        self.pc += 1;
+
+        Ok(true)
+    }
+
+    fn hit_breakpoint(&self, prev_variables: &[i64; 4]) -> bool {
+        self.breakpoints.iter().any(|bp| match bp {
+            Breakpoint::Instruction(index) => *index == self.pc && self.pc < self.program.len(),
+            Breakpoint::Register(var, val) => {
+                self.variables[var] == *val && prev_variables[var] != *val
+            }
+        })
+    }
+
+    /// Executes instructions until the program completes or a breakpoint is hit. Always executes
+    /// at least one instruction, so resuming from a breakpoint makes progress, except when first
+    /// stopping at a breakpoint on instruction 0.
+    fn resume(&mut self) -> Result<Stop, AluError> {
+        if self.pc == 0 && !self.started {
+            self.started = true;
+            let at_start = self
+                .breakpoints
+                .iter()
+                .any(|bp| matches!(bp, Breakpoint::Instruction(0)));
+            if at_start && !self.program.is_empty() {
+                return Ok(Stop::Breakpoint(0));
+            }
+        }
+
+        loop {
+            let prev_variables = self.variables;
+            if !self.step()? {
+                break;
+            }
+            if self.hit_breakpoint(&prev_variables) {
+                return Ok(Stop::Breakpoint(self.pc));
+            }
+        }
+
+        Ok(Stop::Halted)
     }
 
     /// Executes the list of instructions, returning the value of the Z
-    /// variables once complete.
-    fn run(&mut self) -> i64 {
-        for instr in &self.program {
-            match instr {
-                Instruction::Inp(var) => {
-                    let val = self.input.pop().unwrap();
-                    self.variables[var] = val as i64;
+    /// variables once complete. Breakpoints are ignored.
+    fn run(&mut self) -> Result<i64, AluError> {
+        while self.step()? {}
+
+        Ok(self.variables[&Variable::Z])
+    }
+}
+
+/// The inclusive range of values a register may hold at some point in a program.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Range {
+    min: i64,
+    max: i64,
+}
+
+impl Range {
+    const ANY: Range = Range {
+        min: i64::MIN,
+        max: i64::MAX,
+    };
+
+    fn new(min: i64, max: i64) -> Self {
+        Range { min, max }
+    }
+
+    fn constant(val: i64) -> Self {
+        Range::new(val, val)
+    }
+
+    fn as_constant(&self) -> Option<i64> {
+        (self.min == self.max).then_some(self.min)
+    }
+
+    fn contains(&self, val: i64) -> bool {
+        self.min <= val && val <= self.max
+    }
+
+    fn overlaps(&self, other: &Range) -> bool {
+        self.min <= other.max && other.min <= self.max
+    }
+
+    /// Returns the smallest range containing every value, or any value at all if one of the
+    /// calculations overflowed.
+    fn spanning(vals: &[Option<i64>]) -> Self {
+        vals.iter()
+            .try_fold(Range::new(i64::MAX, i64::MIN), |range, val| {
+                val.map(|val| Range::new(range.min.min(val), range.max.max(val)))
+            })
+            .unwrap_or(Range::ANY)
+    }
+}
+
+/// The result of evaluating an instruction over register ranges.
+struct Evaluated {
+    range: Range,
+    /// Whether the instruction leaves the register unchanged.
+    no_op: bool,
+    /// Whether the instruction may raise an error, meaning it must not be removed.
+    may_fail: bool,
+}
+
+fn evaluate_ranges(instr: &Instruction, ranges: &[Range; 4], input: Range) -> Evaluated {
+    let (lhs, rhs) = match instr {
+        Instruction::Inp(_) => {
+            return Evaluated {
+                range: input,
+                no_op: false,
+                may_fail: true,
+            };
+        }
+        Instruction::Add(lhs, rhs)
+        | Instruction::Mul(lhs, rhs)
+        | Instruction::Div(lhs, rhs)
+        | Instruction::Mod(lhs, rhs)
+        | Instruction::Eql(lhs, rhs) => (ranges[lhs], operand_range(rhs, ranges)),
+    };
+
+    let mut no_op = false;
+    let mut may_fail = false;
+    let range = match instr {
+        Instruction::Inp(_) => unreachable!(),
+        Instruction::Add(_, _) => {
+            no_op = rhs.as_constant() == Some(0);
+            Range::spanning(&[lhs.min.checked_add(rhs.min), lhs.max.checked_add(rhs.max)])
+        }
+        Instruction::Mul(_, _) => {
+            no_op = rhs.as_constant() == Some(1);
+            Range::spanning(&[
+                lhs.min.checked_mul(rhs.min),
+                lhs.min.checked_mul(rhs.max),
+                lhs.max.checked_mul(rhs.min),
+                lhs.max.checked_mul(rhs.max),
+            ])
+        }
+        Instruction::Div(_, _) => {
+            no_op = rhs.as_constant() == Some(1);
+            if rhs.contains(0) {
+                may_fail = true;
+                Range::ANY
+            } else {
+                Range::spanning(&[
+                    lhs.min.checked_div(rhs.min),
+                    lhs.min.checked_div(rhs.max),
+                    lhs.max.checked_div(rhs.min),
+                    lhs.max.checked_div(rhs.max),
+                ])
+            }
+        }
+        Instruction::Mod(_, _) => {
+            if lhs.min < 0 || rhs.min <= 0 {
+                may_fail = true;
+                Range::ANY
+            } else if lhs.max < rhs.min {
+                no_op = true;
+                lhs
+            } else {
+                Range::new(0, lhs.max.min(rhs.max - 1))
+            }
+        }
+        Instruction::Eql(_, _) => match (lhs.as_constant(), rhs.as_constant()) {
+            (Some(a), Some(b)) => Range::constant((a == b) as i64),
+            _ if !lhs.overlaps(&rhs) => Range::constant(0),
+            _ => Range::new(0, 1),
+        },
+    };
+
+    // Storing a constant in a register which already holds it doesn't change anything either.
+    let no_op =
+        no_op || (range.as_constant().is_some() && lhs.as_constant() == range.as_constant());
+
+    Evaluated {
+        range,
+        no_op,
+        may_fail,
+    }
+}
+
+fn operand_range(operand: &Operand, ranges: &[Range; 4]) -> Range {
+    match operand {
+        Operand::Literal(l) => Range::constant(*l),
+        Operand::Variable(v) => ranges[v],
+    }
+}
+
+fn destination(instr: &Instruction) -> &Variable {
+    match instr {
+        Instruction::Inp(var)
+        | Instruction::Add(var, _)
+        | Instruction::Mul(var, _)
+        | Instruction::Div(var, _)
+        | Instruction::Mod(var, _)
+        | Instruction::Eql(var, _) => var,
+    }
+}
+
+/// Optimises a program given the ranges its registers start in, the range of values it may be
+/// given as input, and the registers whose values are used once it completes.
+///
+/// A forward pass tracks the range of each register to fold constants, substitute constant
+/// operands with literals, and remove instructions which don't change their register. A backward
+/// pass then removes instructions whose results are never read. Instructions which may fail are
+/// always kept, so the optimised program fails on exactly the same inputs as the original.
+fn optimise(
+    program: &[Instruction],
+    mut ranges: [Range; 4],
+    input: Range,
+    live_out: &[Variable],
+) -> Vec<Instruction> {
+    // Forward pass, recording alongside each instruction whether it is safe to remove. Registers
+    // known to hold a constant aren't written until their value is needed, as most constants are
+    // only ever used as operands, which are replaced with literals.
+    let mut folded: Vec<(Instruction, bool)> = Vec::new();
+    let mut written = ranges;
+    let materialise =
+        |var: Variable, ranges: &[Range; 4], written: &mut [Range; 4], folded: &mut Vec<_>| {
+            if let Some(val) = ranges[&var].as_constant() {
+                if written[&var].as_constant() != Some(val) {
+                    if written[&var].as_constant() != Some(0) {
+                        folded.push((Instruction::Mul(var, Operand::Literal(0)), true));
+                    }
+                    if val != 0 {
+                        folded.push((Instruction::Add(var, Operand::Literal(val)), true));
+                    }
+                    written[&var] = ranges[&var];
                 }
-                Instruction::Add(lhs, rhs) => {
-                    let rhs = match rhs {
-                        Operand::Literal(l) => *l,
-                        Operand::Variable(v) => self.variables[v],
-                    };
-                    self.variables[lhs] += rhs;
+            }
+        };
+
+    for instr in program {
+        let evaluated = evaluate_ranges(instr, &ranges, input);
+        let var = *destination(instr);
+
+        if let Instruction::Inp(_) = instr {
+            folded.push((instr.clone(), false));
+            written[&var] = evaluated.range;
+        } else if evaluated.range.as_constant().is_none() && !evaluated.no_op {
+            materialise(var, &ranges, &mut written, &mut folded);
+
+            let mut instr = instr.clone();
+            if let Instruction::Add(_, rhs)
+            | Instruction::Mul(_, rhs)
+            | Instruction::Div(_, rhs)
+            | Instruction::Mod(_, rhs)
+            | Instruction::Eql(_, rhs) = &mut instr
+            {
+                if let Some(val) = operand_range(rhs, &ranges).as_constant() {
+                    *rhs = Operand::Literal(val);
                 }
-                Instruction::Mul(lhs, rhs) => {
-                    let rhs = match rhs {
-                        Operand::Literal(l) => *l,
-                        Operand::Variable(v) => self.variables[v],
-                    };
-                    self.variables[lhs] *= rhs;
+            }
+            folded.push((instr, !evaluated.may_fail));
+            written[&var] = evaluated.range;
+        }
+
+        ranges[&var] = evaluated.range;
+    }
+    for var in live_out {
+        materialise(*var, &ranges, &mut written, &mut folded);
+    }
+
+    // Backward pass, tracking which registers are read before being overwritten.
+    let mut live = [false; 4];
+    for var in live_out {
+        live[var] = true;
+    }
+    let mut optimised = Vec::new();
+    for (instr, removable) in folded.into_iter().rev() {
+        let var = destination(&instr);
+        if removable && !live[var] {
+            continue;
+        }
+
+        live[var] = !matches!(
+            &instr,
+            Instruction::Inp(_) | Instruction::Mul(_, Operand::Literal(0))
+        );
+        if let Instruction::Add(_, Operand::Variable(rhs))
+        | Instruction::Mul(_, Operand::Variable(rhs))
+        | Instruction::Div(_, Operand::Variable(rhs))
+        | Instruction::Mod(_, Operand::Variable(rhs))
+        | Instruction::Eql(_, Operand::Variable(rhs)) = &instr
+        {
+            live[rhs] = true;
+        }
+        optimised.push(instr);
+    }
+    optimised.reverse();
+
+    optimised
+}
+
+type CompiledInstruction =
+    Box<dyn Fn(&mut [i64; 4], &mut std::slice::Iter<u64>) -> Result<(), AluError>>;
+
+/// A program lowered to a list of closures, with register lookups and operand types resolved
+/// ahead of time rather than on every execution.
+struct CompiledProgram {
+    instructions: Vec<CompiledInstruction>,
+}
+
+impl CompiledProgram {
+    fn new(program: &[Instruction]) -> Self {
+        let instructions = program
+            .iter()
+            .enumerate()
+            .map(|(pc, instr)| match instr {
+                Instruction::Inp(var) => {
+                    let dest = var.register();
+                    Box::new(
+                        move |variables: &mut [i64; 4], input: &mut std::slice::Iter<u64>| {
+                            variables[dest] =
+                                *input.next().ok_or(AluError::InputUnderflow { pc })? as i64;
+                            Ok(())
+                        },
+                    )
                 }
+                Instruction::Add(lhs, rhs) => compile_binary(lhs, rhs, |a, b| Ok(a + b)),
+                Instruction::Mul(lhs, rhs) => compile_binary(lhs, rhs, |a, b| Ok(a * b)),
                 Instruction::Div(lhs, rhs) => {
-                    let rhs = match rhs {
-                        Operand::Literal(l) => *l,
-                        Operand::Variable(v) => self.variables[v],
-                    };
-                    self.variables[lhs] /= rhs;
+                    compile_binary(lhs, rhs, move |a, b| divide(a, b, pc))
                 }
                 Instruction::Mod(lhs, rhs) => {
-                    let rhs = match rhs {
-                        Operand::Literal(l) => *l,
-                        Operand::Variable(v) => self.variables[v],
-                    };
-                    self.variables[lhs] %= rhs;
+                    compile_binary(lhs, rhs, move |a, b| modulo(a, b, pc))
                 }
-                Instruction::Eql(lhs, rhs) => {
-                    let rhs = match rhs {
-                        Operand::Literal(l) => *l,
-                        Operand::Variable(v) => self.variables[v],
-                    };
-                    self.variables[lhs] = (self.variables[lhs] == rhs) as i64;
+                Instruction::Eql(lhs, rhs) => compile_binary(lhs, rhs, |a, b| Ok((a == b) as i64)),
+            })
+            .collect();
+
+        CompiledProgram { instructions }
+    }
+
+    /// Executes the program, returning the value of the Z variable once complete.
+    fn run(&self, input: &[u64], z: i64) -> Result<i64, AluError> {
+        let mut variables = [0; 4];
+        variables[&Variable::Z] = z;
+        let mut input = input.iter();
+
+        for instr in &self.instructions {
+            instr(&mut variables, &mut input)?;
+        }
+
+        Ok(variables[&Variable::Z])
+    }
+}
+
+fn compile_binary<F>(lhs: &Variable, rhs: &Operand, op: F) -> CompiledInstruction
+where
+    F: Fn(i64, i64) -> Result<i64, AluError> + 'static,
+{
+    let dest = lhs.register();
+    match rhs {
+        Operand::Literal(l) => {
+            let l = *l;
+            Box::new(move |variables, _| {
+                variables[dest] = op(variables[dest], l)?;
+                Ok(())
+            })
+        }
+        Operand::Variable(v) => {
+            let src = v.register();
+            Box::new(move |variables, _| {
+                variables[dest] = op(variables[dest], variables[src])?;
+                Ok(())
+            })
+        }
+    }
+}
+
+/// Splits a program in to chunks, each starting with an input instruction. Any instructions before
+/// the first input form a chunk of their own.
+fn split_chunks(program: &[Instruction]) -> Vec<&[Instruction]> {
+    let mut starts = program
+        .iter()
+        .positions(|instr| matches!(instr, Instruction::Inp(_)))
+        .collect_vec();
+    if starts.first() != Some(&0) {
+        starts.insert(0, 0);
+    }
+
+    starts
+        .iter()
+        .zip(starts.iter().skip(1).chain(std::iter::once(&program.len())))
+        .map(|(start, end)| &program[*start..*end])
+        .collect()
+}
+
+/// Formats the program as text which can be parsed back in, with a comment line before each
+/// chunk giving the range of instruction indices it covers.
+fn disassemble(program: &[Instruction]) -> String {
+    let mut text = String::new();
+    let mut start = 0;
+    for (i, chunk) in split_chunks(program).into_iter().enumerate() {
+        if i > 0 {
+            text.push('\n');
+        }
+        text.push_str(&format!(
+            "; chunk {}: instructions {}..{}\n",
+            i,
+            start,
+            start + chunk.len()
+        ));
+        for instr in chunk {
+            text.push_str(&format!("{}\n", instr));
+        }
+        start += chunk.len();
+    }
+
+    text
+}
+
+/// A symbolic expression for the value of a register in terms of the values at the start of a
+/// chunk. Comparisons evaluate to 1 if true and 0 if false, as with the `eql` instruction.
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    Literal(i64),
+    /// The value a register held at the start of the chunk.
+    Register(Variable),
+    /// The value read by an input instruction in to the given register.
+    Input(Variable),
+    Add(Box<Expr>, Box<Expr>),
+    Mul(Box<Expr>, Box<Expr>),
+    Div(Box<Expr>, Box<Expr>),
+    Mod(Box<Expr>, Box<Expr>),
+    Eql(Box<Expr>, Box<Expr>),
+    NotEql(Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    fn is_condition(&self) -> bool {
+        matches!(self, Expr::Eql(_, _) | Expr::NotEql(_, _))
+    }
+
+    fn add(lhs: Expr, rhs: Expr) -> Expr {
+        match (lhs, rhs) {
+            (Expr::Literal(a), Expr::Literal(b)) => Expr::Literal(a + b),
+            (Expr::Literal(0), e) | (e, Expr::Literal(0)) => e,
+            (Expr::Add(a, b), Expr::Literal(c)) if matches!(*b, Expr::Literal(_)) => {
+                Expr::add(*a, Expr::add(*b, Expr::Literal(c)))
+            }
+            (a, b) => Expr::Add(Box::new(a), Box::new(b)),
+        }
+    }
+
+    fn mul(lhs: Expr, rhs: Expr) -> Expr {
+        match (lhs, rhs) {
+            (Expr::Literal(a), Expr::Literal(b)) => Expr::Literal(a * b),
+            (Expr::Literal(0), _) | (_, Expr::Literal(0)) => Expr::Literal(0),
+            (Expr::Literal(1), e) | (e, Expr::Literal(1)) => e,
+            (a, b) => Expr::Mul(Box::new(a), Box::new(b)),
+        }
+    }
+
+    fn div(lhs: Expr, rhs: Expr) -> Expr {
+        match (lhs, rhs) {
+            (Expr::Literal(a), Expr::Literal(b)) if b != 0 => Expr::Literal(a / b),
+            (e, Expr::Literal(1)) => e,
+            (a, b) => Expr::Div(Box::new(a), Box::new(b)),
+        }
+    }
+
+    fn modulo(lhs: Expr, rhs: Expr) -> Expr {
+        match (lhs, rhs) {
+            (Expr::Literal(a), Expr::Literal(b)) if a >= 0 && b > 0 => Expr::Literal(a % b),
+            (a, b) => Expr::Mod(Box::new(a), Box::new(b)),
+        }
+    }
+
+    fn eql(lhs: Expr, rhs: Expr) -> Expr {
+        match (lhs, rhs) {
+            (Expr::Literal(a), Expr::Literal(b)) => Expr::Literal((a == b) as i64),
+            (Expr::Eql(a, b), Expr::Literal(0)) => Expr::NotEql(a, b),
+            (Expr::NotEql(a, b), Expr::Literal(0)) => Expr::Eql(a, b),
+            (cond, Expr::Literal(1)) if cond.is_condition() => cond,
+            (a, b) => Expr::Eql(Box::new(a), Box::new(b)),
+        }
+    }
+
+    /// Returns the first comparison found within the expression.
+    fn find_condition(&self) -> Option<&Expr> {
+        match self {
+            Expr::Literal(_) | Expr::Register(_) | Expr::Input(_) => None,
+            Expr::Eql(_, _) | Expr::NotEql(_, _) => Some(self),
+            Expr::Add(a, b) | Expr::Mul(a, b) | Expr::Div(a, b) | Expr::Mod(a, b) => {
+                a.find_condition().or_else(|| b.find_condition())
+            }
+        }
+    }
+
+    /// Replaces the condition, and its negation, with the result of assuming the condition holds
+    /// or not, simplifying the expression as it is rebuilt.
+    fn assume(&self, cond: &Expr, holds: bool) -> Expr {
+        if self == cond {
+            return Expr::Literal(holds as i64);
+        }
+        match (self, cond) {
+            (Expr::Eql(a, b), Expr::NotEql(c, d)) | (Expr::NotEql(a, b), Expr::Eql(c, d))
+                if a == c && b == d =>
+            {
+                return Expr::Literal(!holds as i64);
+            }
+            _ => {}
+        }
+
+        match self {
+            Expr::Literal(_) | Expr::Register(_) | Expr::Input(_) => self.clone(),
+            Expr::Add(a, b) => Expr::add(a.assume(cond, holds), b.assume(cond, holds)),
+            Expr::Mul(a, b) => Expr::mul(a.assume(cond, holds), b.assume(cond, holds)),
+            Expr::Div(a, b) => Expr::div(a.assume(cond, holds), b.assume(cond, holds)),
+            Expr::Mod(a, b) => Expr::modulo(a.assume(cond, holds), b.assume(cond, holds)),
+            Expr::Eql(a, b) => Expr::eql(a.assume(cond, holds), b.assume(cond, holds)),
+            Expr::NotEql(a, b) => Expr::eql(
+                Expr::eql(a.assume(cond, holds), b.assume(cond, holds)),
+                Expr::Literal(0),
+            ),
+        }
+    }
+
+    /// Collects the registers whose values at the start of the chunk the expression depends on.
+    fn registers_used(&self, used: &mut [bool; 4]) {
+        match self {
+            Expr::Literal(_) | Expr::Input(_) => {}
+            Expr::Register(var) => used[var] = true,
+            Expr::Add(a, b)
+            | Expr::Mul(a, b)
+            | Expr::Div(a, b)
+            | Expr::Mod(a, b)
+            | Expr::Eql(a, b)
+            | Expr::NotEql(a, b) => {
+                a.registers_used(used);
+                b.registers_used(used);
+            }
+        }
+    }
+
+    fn precedence(&self) -> u8 {
+        match self {
+            Expr::Eql(_, _) | Expr::NotEql(_, _) => 0,
+            Expr::Add(_, _) => 1,
+            Expr::Mul(_, _) | Expr::Div(_, _) | Expr::Mod(_, _) => 2,
+            Expr::Literal(_) | Expr::Register(_) | Expr::Input(_) => 3,
+        }
+    }
+
+    /// Formats the expression with any comparisons within it lifted out as `if` expressions.
+    fn to_pseudocode(&self) -> String {
+        match self.find_condition() {
+            Some(cond) if self != cond => format!(
+                "if {} {{ {} }} else {{ {} }}",
+                cond,
+                self.assume(cond, true).to_pseudocode(),
+                self.assume(cond, false).to_pseudocode()
+            ),
+            _ => self.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Operands of a comparison are always bracketed for readability, otherwise only where
+        // precedence requires it.
+        let operand = |e: &Expr, min_precedence: u8| {
+            if e.precedence() < min_precedence || (self.is_condition() && e.precedence() < 3) {
+                format!("({})", e)
+            } else {
+                e.to_string()
+            }
+        };
+
+        let prec = self.precedence();
+        match self {
+            Expr::Literal(l) => write!(f, "{}", l),
+            Expr::Register(var) | Expr::Input(var) => write!(f, "{}", var),
+            // Addition and multiplication are associative, so don't need brackets on either side.
+            Expr::Add(a, b) => match **b {
+                Expr::Literal(l) if l < 0 => write!(f, "{} - {}", operand(a, prec), -l),
+                _ => write!(f, "{} + {}", operand(a, prec), operand(b, prec)),
+            },
+            Expr::Mul(a, b) => write!(f, "{} * {}", operand(a, prec), operand(b, prec)),
+            Expr::Div(a, b) => write!(f, "{} / {}", operand(a, prec), operand(b, prec + 1)),
+            Expr::Mod(a, b) => write!(f, "{} % {}", operand(a, prec), operand(b, prec + 1)),
+            Expr::Eql(a, b) => write!(f, "{} == {}", operand(a, prec), operand(b, prec)),
+            Expr::NotEql(a, b) => write!(f, "{} != {}", operand(a, prec), operand(b, prec)),
+        }
+    }
+}
+
+/// Symbolically executes a chunk, returning an expression for each register's final value.
+fn symbolic_execute(chunk: &[Instruction]) -> [Expr; 4] {
+    let mut registers = [
+        Expr::Register(Variable::W),
+        Expr::Register(Variable::X),
+        Expr::Register(Variable::Y),
+        Expr::Register(Variable::Z),
+    ];
+
+    for instr in chunk {
+        let (var, rhs) = match instr {
+            Instruction::Inp(var) => {
+                registers[var] = Expr::Input(*var);
+                continue;
+            }
+            Instruction::Add(var, rhs)
+            | Instruction::Mul(var, rhs)
+            | Instruction::Div(var, rhs)
+            | Instruction::Mod(var, rhs)
+            | Instruction::Eql(var, rhs) => (var, rhs),
+        };
+        let lhs = registers[var].clone();
+        let rhs = match rhs {
+            Operand::Literal(l) => Expr::Literal(*l),
+            Operand::Variable(v) => registers[v].clone(),
+        };
+        registers[var] = match instr {
+            Instruction::Inp(_) => unreachable!(),
+            Instruction::Add(_, _) => Expr::add(lhs, rhs),
+            Instruction::Mul(_, _) => Expr::mul(lhs, rhs),
+            Instruction::Div(_, _) => Expr::div(lhs, rhs),
+            Instruction::Mod(_, _) => Expr::modulo(lhs, rhs),
+            Instruction::Eql(_, _) => Expr::eql(lhs, rhs),
+        };
+    }
+
+    registers
+}
+
+/// Describes each chunk of the program as pseudocode assignments. Only the registers whose values
+/// are later used are shown, working backwards from Z being the program's result.
+fn decompile(program: &[Instruction]) -> String {
+    let chunks = split_chunks(program);
+    let mut needed = [false; 4];
+    needed[&Variable::Z] = true;
+
+    let mut descriptions = Vec::new();
+    for (i, chunk) in chunks.iter().enumerate().rev() {
+        let registers = symbolic_execute(chunk);
+
+        let mut description = format!("chunk {}:\n", i);
+        let mut used = [false; 4];
+        for var in [Variable::W, Variable::X, Variable::Y, Variable::Z] {
+            if needed[&var] {
+                registers[&var].registers_used(&mut used);
+                if registers[&var] != Expr::Register(var) {
+                    description.push_str(&format!(
+                        "  {} = {}\n",
+                        var,
+                        registers[&var].to_pseudocode()
+                    ));
                 }
             }
         }
+        descriptions.push(description);
+        needed = used;
+    }
+    descriptions.reverse();
+
+    descriptions.join("\n")
+}
 
-        self.variables[&Variable::Z]
+fn parse_breakpoint(arg: &str) -> Result<Breakpoint> {
+    match arg.split_once('=') {
+        Some((var, val)) => {
+            let var = parse_var(var)
+                .finish()
+                .map_err(|e| anyhow!("Invalid register in breakpoint {}: {:?}", arg, e))?
+                .1;
+            Ok(Breakpoint::Register(var, val.parse()?))
+        }
+        None => Ok(Breakpoint::Instruction(arg.parse()?)),
     }
 }
+
+/// Runs the program on a single model number, printing every instruction executed and pausing
+/// at breakpoints. Usage: `day24 trace <model number> [<index> | <register>=<value>]...`
+fn trace(program: &[Instruction], args: &[String]) -> Result<()> {
+    let (model_number, breakpoints) = args
+        .split_first()
+        .ok_or_else(|| anyhow!("Usage: day24 trace <model number> [breakpoint]..."))?;
+    let input = model_number
+        .chars()
+        .map(|c| {
+            c.to_digit(10)
+                .map(u64::from)
+                .ok_or_else(|| anyhow!("Invalid digit {} in model number", c))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut alu = Alu::new(program, &input, 0);
+    alu.enable_trace();
+    for breakpoint in breakpoints {
+        alu.add_breakpoint(parse_breakpoint(breakpoint)?);
+    }
+
+    loop {
+        let stop = alu.resume();
+        for entry in alu.take_trace() {
+            println!("{}", entry);
+        }
+        match stop? {
+            Stop::Halted => break,
+            Stop::Breakpoint(pc) if pc == program.len() => println!("-- breakpoint at end"),
+            Stop::Breakpoint(pc) => println!("-- breakpoint before instruction {}", pc),
+        }
+    }
+
+    println!("z = {}", alu.variables[&Variable::Z]);
+
+    Ok(())
+}
+
 fn main() -> Result<()> {
     let input = std::fs::read_to_string("res/day24")?;
     let program = parse_input(&input)
         .map_err(|e| anyhow!("Error parsing input: {:?}", e))?
         .1;
 
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    if let Some((command, args)) = args.split_first() {
+        return match command.as_str() {
+            "trace" => trace(&program, args),
+            "disassemble" => {
+                print!("{}", disassemble(&program));
+                Ok(())
+            }
+            "decompile" => {
+                print!("{}", decompile(&program));
+                Ok(())
+            }
+            _ => Err(anyhow!("Unknown command {}", command)),
+        };
+    }
+
     // Looking at the input, we can see that the program is split in to 18 chunks.
     // Each one reads a single input in to register W, and clears X and Y before use.
     // Therefore Z is the only register whose value affects the result of the chunk.
@@ -219,9 +1100,25 @@ fn main() -> Result<()> {
     for program_chunk in program.chunks(18) {
         let mut new_zs = HashMap::new();
 
+        // Each chunk is optimised and compiled knowing the range of Z values it will start with.
+        // As only Z is carried on to the next chunk, the other registers need not be computed.
+        let (min_z, max_z) = prev_zs.keys().minmax().into_option().unwrap();
+        let entry_ranges = [
+            Range::constant(0),
+            Range::constant(0),
+            Range::constant(0),
+            Range::new(*min_z, *max_z),
+        ];
+        let optimised = optimise(
+            program_chunk,
+            entry_ranges,
+            Range::new(1, 9),
+            &[Variable::Z],
+        );
+        let compiled = CompiledProgram::new(&optimised);
+
         for (prev_z, new_input) in prev_zs.keys().cartesian_product(1..=9) {
-            let mut alu = Alu::new(program_chunk, &[new_input], *prev_z);
-            let new_z = alu.run();
+            let new_z = compiled.run(&[new_input], *prev_z)?;
 
             let (mut min_input, mut max_input) = prev_zs.get(prev_z).cloned().unwrap_or_default();
             min_input = min_input * 10 + new_input;
@@ -245,6 +1142,16 @@ fn main() -> Result<()> {
         .get(&0)
         .ok_or_else(|| anyhow!("Failed to find valid model number"))?;
 
+    // Check the model numbers are accepted when running the whole program in one go.
+    for model_number in [max, min] {
+        let input = model_number
+            .to_string()
+            .chars()
+            .filter_map(|c| c.to_digit(10).map(u64::from))
+            .collect_vec();
+        assert_eq!(Alu::new(&program, &input, 0).run()?, 0);
+    }
+
     let result_a = *max;
     assert_eq!(result_a, 12996997829399);
     println!("Day 24, part A: {}", result_a);
@@ -255,3 +1162,105 @@ fn main() -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn program(text: &str) -> Vec<Instruction> {
+        parse_input(text).unwrap().1
+    }
+
+    #[test]
+    fn breakpoints() {
+        let program = program("inp w\nadd x w\nadd x 1\nmul z x");
+
+        // Check a breakpoint on the first instruction stops before anything runs, and only once
+        let mut alu = Alu::new(&program, &[4], 0);
+        alu.add_breakpoint(Breakpoint::Instruction(0));
+        alu.add_breakpoint(Breakpoint::Instruction(2));
+        assert_eq!(alu.resume(), Ok(Stop::Breakpoint(0)));
+        assert_eq!(alu.variables, [0; 4]);
+        assert_eq!(alu.resume(), Ok(Stop::Breakpoint(2)));
+        assert_eq!(alu.variables[&Variable::X], 4);
+        assert_eq!(alu.resume(), Ok(Stop::Halted));
+
+        // Check register breakpoints stop after the instruction which set the value
+        let mut alu = Alu::new(&program, &[4], 0);
+        alu.add_breakpoint(Breakpoint::Register(Variable::X, 5));
+        assert_eq!(alu.resume(), Ok(Stop::Breakpoint(3)));
+        assert_eq!(alu.resume(), Ok(Stop::Halted));
+        assert_eq!(alu.variables[&Variable::Z], 0);
+
+        // Check a register set by the last instruction still stops, at the end of the program
+        let program = self::program("inp w\nadd z w\nadd z 1");
+        let mut alu = Alu::new(&program, &[4], 0);
+        alu.add_breakpoint(Breakpoint::Register(Variable::Z, 5));
+        assert_eq!(alu.resume(), Ok(Stop::Breakpoint(3)));
+        assert_eq!(alu.variables[&Variable::Z], 5);
+        assert_eq!(alu.resume(), Ok(Stop::Halted));
+
+        // Check an instruction breakpoint past the end never stops
+        let mut alu = Alu::new(&program, &[4], 0);
+        alu.add_breakpoint(Breakpoint::Instruction(3));
+        assert_eq!(alu.resume(), Ok(Stop::Halted));
+    }
+
+    #[test]
+    fn parse_comments() {
+        // Check comments are accepted on their own line or after an instruction
+        let program = program("; start\ninp w\n\nadd x 1 ; bump\n  mul x w;\n");
+        assert_eq!(
+            program,
+            vec![
+                Instruction::Inp(Variable::W),
+                Instruction::Add(Variable::X, Operand::Literal(1)),
+                Instruction::Mul(Variable::X, Operand::Variable(Variable::W)),
+            ]
+        );
+
+        // Check anything else left over is an error rather than being dropped
+        assert!(parse_input("inp w\nadd x 1 bump\nmul x w").is_err());
+        assert!(parse_input("inp w\nfoo\nmul x w").is_err());
+    }
+
+    #[test]
+    fn disassemble_round_trip() {
+        let input = std::fs::read_to_string("res/day24").unwrap();
+        let program = program(&input);
+
+        // Check the program can be written back out as text and parsed again
+        assert_eq!(parse_input(&disassemble(&program)).unwrap().1, program);
+    }
+
+    #[test]
+    fn compiled_chunks_match_interpreter() {
+        let input = std::fs::read_to_string("res/day24").unwrap();
+        let program = program(&input);
+
+        // Z values either side of the multiples of 26 the chunks divide and take the modulo by
+        let zs = [
+            0, 1, 25, 26, 27, 100, 675, 676, 677, 12_345, 456_976, 9_999_999,
+        ];
+        for chunk in program.chunks(18) {
+            let entry_ranges = [
+                Range::constant(0),
+                Range::constant(0),
+                Range::constant(0),
+                Range::new(0, 9_999_999),
+            ];
+            let optimised = optimise(chunk, entry_ranges, Range::new(1, 9), &[Variable::Z]);
+            let compiled = CompiledProgram::new(&optimised);
+
+            for (z, digit) in zs.iter().cartesian_product(1..=9) {
+                assert_eq!(
+                    compiled.run(&[digit], *z),
+                    Alu::new(chunk, &[digit], *z).run(),
+                    "z = {}, digit = {}",
+                    z,
+                    digit
+                );
+            }
+        }
+    }
+}
@@ -1,89 +1,204 @@
+use std::{fmt, time::Instant};
+
 use anyhow::{anyhow, Result};
-use euclid::{default::Point2D, point2};
+use euclid::{default::Vector2D, vec2};
+use itertools::Itertools;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+/// A herd of sea cucumbers, which all try to move the same way at the same time.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Herd {
+    symbol: char,
+    direction: Vector2D<isize>,
+}
 
-#[derive(Clone, Debug)]
-enum Cucumber {
-    East,
-    South,
+/// The herds in the puzzle. East moves before south.
+fn puzzle_herds() -> Vec<Herd> {
+    vec![
+        Herd {
+            symbol: '>',
+            direction: vec2(1, 0),
+        },
+        Herd {
+            symbol: 'v',
+            direction: vec2(0, 1),
+        },
+    ]
 }
 
+const EMPTY: u8 = 0;
+/// Marks a cell whose cucumber is about to move, part way through a step
+const MOVING: u8 = u8::MAX;
+
+/// The cells of the world, each holding one past the index of the herd in it, or `EMPTY`.
+/// Cucumbers in the same herd can't be told apart, so this is all there is to know about them.
 #[derive(Debug, Clone, PartialEq)]
 struct World {
     width: usize,
     height: usize,
-    east_cucumbers: Vec<Point2D<usize>>,
-    south_cucumbers: Vec<Point2D<usize>>,
+    /// The herds, in the order they move
+    herds: Vec<Herd>,
+    cells: Vec<u8>,
 }
 
 impl World {
-    fn new(width: usize, height: usize) -> Self {
-        World {
+    /// Creates an empty world. Each cell holds a herd in a byte, alongside `EMPTY` and `MOVING`,
+    /// so there can be at most 254 herds. Both dimensions must be at least 1.
+    fn new(width: usize, height: usize, herds: Vec<Herd>) -> Result<Self> {
+        if width == 0 || height == 0 {
+            return Err(anyhow!("World is empty: {}x{}", width, height));
+        }
+        if herds.len() >= MOVING as usize {
+            return Err(anyhow!(
+                "Too many herds: {}, at most {} are supported",
+                herds.len(),
+                MOVING - 1
+            ));
+        }
+
+        Ok(World {
             width,
             height,
-            east_cucumbers: Vec::default(),
-            south_cucumbers: Vec::default(),
+            herds,
+            cells: vec![EMPTY; width * height],
+        })
+    }
+
+    fn herd_at(&self, x: usize, y: usize) -> Option<usize> {
+        match self.cells[y * self.width + x] {
+            EMPTY => None,
+            cell => Some(cell as usize - 1),
         }
     }
 
-    fn create_map(&self) -> Vec<Option<Cucumber>> {
-        let mut map = vec![None; self.width * self.height];
+    fn set(&mut self, x: usize, y: usize, herd: Option<usize>) {
+        self.cells[y * self.width + x] = herd.map_or(EMPTY, |herd| herd as u8 + 1);
+    }
+
+    /// Moves each herd in turn, returning how many cucumbers moved
+    fn step(&mut self) -> usize {
+        let mut moves = 0;
+        // One row of cells, shifted to line up with the row a herd moves into
+        let mut scratch = vec![EMPTY; self.width];
 
-        for pos in &self.east_cucumbers {
-            map[pos.y * self.width + pos.x] = Some(Cucumber::East);
-        }
-        for pos in &self.south_cucumbers {
-            map[pos.y * self.width + pos.x] = Some(Cucumber::South);
+        for herd in 0..self.herds.len() {
+            let cell = herd as u8 + 1;
+            let direction = self.herds[herd].direction;
+            // How far along each axis a cucumber moves, wrapped to be within the world
+            let dx = direction.x.rem_euclid(self.width as isize) as usize;
+            let dy = direction.y.rem_euclid(self.height as isize) as usize;
+            let (width, height) = (self.width, self.height);
+            let row = |y: usize| (y * width)..((y + 1) * width);
+
+            // All of the herd look before any of them move, so first mark those which can. A
+            // marked cell is still occupied, so nothing else will move into it.
+            for y in 0..height {
+                // Line up the row being moved into with the cells moving into it
+                scratch.copy_from_slice(&self.cells[row((y + dy) % height)]);
+                scratch.rotate_left(dx);
+
+                // Written without branches, as on a crowded world they're hard to predict
+                for (current, destination) in self.cells[row(y)].iter_mut().zip(&scratch) {
+                    let moving = (*current == cell) & (*destination == EMPTY);
+                    *current = if moving { MOVING } else { *current };
+                    moves += moving as usize;
+                }
+            }
+
+            // Every cucumber in the herd moves the same way, so no two can move to the same cell,
+            // and none can move to a cell which another is moving from or has moved to
+            for y in 0..height {
+                for (current, arriving) in self.cells[row(y)].iter_mut().zip(scratch.iter_mut()) {
+                    let moving = *current == MOVING;
+                    *arriving = cell * moving as u8;
+                    *current = if moving { EMPTY } else { *current };
+                }
+                scratch.rotate_right(dx);
+
+                // Cells being moved into are empty, so this only fills those
+                for (destination, arriving) in
+                    self.cells[row((y + dy) % height)].iter_mut().zip(&scratch)
+                {
+                    *destination |= *arriving;
+                }
+            }
         }
 
-        map
+        moves
     }
+}
 
-    #[allow(dead_code)]
-    fn print_map(&self) {
-        let map = self.create_map();
-
-        let mut map_str = String::new();
+impl fmt::Display for World {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         for y in 0..self.height {
             for x in 0..self.width {
-                let c = match map[y * self.width + x] {
-                    Some(Cucumber::East) => '>',
-                    Some(Cucumber::South) => 'v',
+                let c = match self.herd_at(x, y) {
+                    Some(herd) => self.herds[herd].symbol,
                     None => '.',
                 };
-                map_str.push(c);
+                write!(f, "{}", c)?;
             }
-            map_str.push('\n');
+            writeln!(f)?;
         }
 
-        println!("{}", &map_str);
+        Ok(())
     }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Outcome {
+    /// No cucumbers moved on this step, so none ever will again
+    Settled { step: usize },
+    /// The world after `start` steps recurs every `period` steps
+    Cycle { start: usize, period: usize },
+}
 
-    fn step(&mut self) -> bool {
-        let mut modified = false;
+/// Steps the world until the cucumbers stop moving or start repeating themselves, giving up after
+/// `max_steps`. Repeats are found with Brent's algorithm, so only two worlds are kept at once.
+fn simulate(world: &World, max_steps: usize) -> Option<Outcome> {
+    let mut saved = world.clone();
+    let mut current = world.clone();
+    let mut steps = 0;
+    let mut power = 1;
+    let mut period = 0;
 
-        let map = self.create_map();
-        for c in &mut self.east_cucumbers {
-            let new_pos = point2((c.x + 1) % self.width, c.y);
-            if map[new_pos.y * self.width + new_pos.x].is_none() {
-                *c = new_pos;
-                modified = true;
-            }
+    loop {
+        if steps == max_steps {
+            return None;
+        }
+        steps += 1;
+        if current.step() == 0 {
+            return Some(Outcome::Settled { step: steps });
         }
+        period += 1;
 
-        let map = self.create_map();
-        for c in &mut self.south_cucumbers {
-            let new_pos = point2(c.x, (c.y + 1) % self.height);
-            if map[new_pos.y * self.width + new_pos.x].is_none() {
-                *c = new_pos;
-                modified = true;
-            }
+        if current == saved {
+            break;
         }
+        if period == power {
+            saved = current.clone();
+            power *= 2;
+            period = 0;
+        }
+    }
 
-        modified
+    // Find where the cycle starts by stepping two worlds `period` steps apart until they meet
+    let mut behind = world.clone();
+    let mut ahead = world.clone();
+    (0..period).for_each(|_| {
+        ahead.step();
+    });
+    let mut start = 0;
+    while behind != ahead {
+        behind.step();
+        ahead.step();
+        start += 1;
     }
+
+    Some(Outcome::Cycle { start, period })
 }
 
-fn parse_input(input: &str) -> Result<World> {
+fn parse_input(input: &str, herds: Vec<Herd>) -> Result<World> {
     let width = input
         .lines()
         .next()
@@ -92,40 +207,149 @@ fn parse_input(input: &str) -> Result<World> {
         .count();
     let height = input.lines().count();
 
-    let world = input
-        .lines()
-        .enumerate()
-        .flat_map(|(y, line)| line.chars().enumerate().map(move |(x, c)| (x, y, c)))
-        .fold(World::new(width, height), |mut world, (x, y, c)| {
-            match c {
-                '>' => world.east_cucumbers.push(point2(x, y)),
-                'v' => world.south_cucumbers.push(point2(x, y)),
-                _ => {}
+    let mut world = World::new(width, height, herds)?;
+    for (y, line) in input.lines().enumerate() {
+        for (x, c) in line.chars().enumerate() {
+            if c == '.' {
+                continue;
             }
-            world
-        });
+            let herd = world
+                .herds
+                .iter()
+                .position(|herd| herd.symbol == c)
+                .ok_or_else(|| anyhow!("Unknown sea cucumber: {}", c))?;
+            world.set(x, y, Some(herd));
+        }
+    }
 
     Ok(world)
 }
 
+/// Steps a large world of randomly placed cucumbers and reports how quickly it went.
+fn benchmark(width: usize, height: usize, steps: usize) -> Result<()> {
+    // Seeded, so each run uses the same world
+    let mut rng = StdRng::seed_from_u64(25);
+
+    let mut world = World::new(width, height, puzzle_herds())?;
+    for y in 0..height {
+        for x in 0..width {
+            // Fill about half of the cells, evenly split between the herds
+            match rng.gen_range(0..4) {
+                0 => world.set(x, y, Some(0)),
+                1 => world.set(x, y, Some(1)),
+                _ => {}
+            }
+        }
+    }
+
+    let start = Instant::now();
+    let moves = (0..steps).map(|_| world.step()).collect_vec();
+    let elapsed = start.elapsed().as_secs_f64();
+
+    println!(
+        "{}x{}: {} steps in {:.3}s, {:.1} steps/s, {:.1}M cells/s, {} moves in the last step",
+        width,
+        height,
+        steps,
+        elapsed,
+        steps as f64 / elapsed,
+        (width * height * steps) as f64 / elapsed / 1e6,
+        moves.last().unwrap_or(&0)
+    );
+
+    Ok(())
+}
+
 fn main() -> Result<()> {
     let input = std::fs::read_to_string("res/day25")?;
-    let mut world = parse_input(&input)?;
-
-    let mut num_steps = 0;
-    loop {
-        let mut new_world = world.clone();
-        new_world.step();
-        num_steps += 1;
+    let world = parse_input(&input, puzzle_herds())?;
 
-        if new_world == world {
-            break;
+    let args = std::env::args().skip(1).collect_vec();
+    match args.iter().map(String::as_str).collect_vec().as_slice() {
+        [] => {}
+        ["bench", width, height, steps] => {
+            return benchmark(width.parse()?, height.parse()?, steps.parse()?);
         }
-
-        world = new_world;
+        _ => return Err(anyhow!("Usage: day25 [bench <width> <height> <steps>]")),
     }
 
+    let num_steps = match simulate(&world, 10_000) {
+        Some(Outcome::Settled { step }) => step,
+        outcome => return Err(anyhow!("Sea cucumbers didn't settle: {:?}", outcome)),
+    };
+    assert_eq!(num_steps, 498);
+
     println!("Day 25, part A: {}", num_steps);
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn example() -> Result<()> {
+        // Check the example from the puzzle, and that the world can be printed back out
+        let example = "v...>>.vv>\n.vv>>.vv..\n>>.>v>...v\n>>v>>.>.v.\nv>v.vv.v..\n\
+                       >.>>..v...\n.vv..>.>v.\nv.v..>>v.v\n....v..v.>\n";
+        let world = parse_input(example, puzzle_herds())?;
+        assert_eq!(world.to_string(), example);
+        assert_eq!(simulate(&world, 1000), Some(Outcome::Settled { step: 58 }));
+
+        Ok(())
+    }
+
+    #[test]
+    fn cycles() -> Result<()> {
+        // Check herds going any way around a world with nothing in their way cycle forever
+        let herds = vec![
+            Herd {
+                symbol: '<',
+                direction: vec2(-1, 0),
+            },
+            Herd {
+                symbol: '^',
+                direction: vec2(0, -1),
+            },
+            Herd {
+                symbol: '/',
+                direction: vec2(1, 1),
+            },
+        ];
+        let lonely = parse_input("<....\n.....\n.....", herds.clone())?;
+        assert_eq!(
+            simulate(&lonely, 100),
+            Some(Outcome::Cycle {
+                start: 0,
+                period: 5
+            })
+        );
+        let crowd = parse_input("^..\n/..\n...\n...", herds)?;
+        assert!(matches!(simulate(&crowd, 100), Some(Outcome::Cycle { .. })));
+
+        Ok(())
+    }
+
+    #[test]
+    fn herd_limit() {
+        let herds = |count: u32| {
+            (0..count)
+                .map(|i| Herd {
+                    symbol: char::from_u32(0x100 + i).unwrap(),
+                    direction: vec2(1, 0),
+                })
+                .collect_vec()
+        };
+
+        // Check as many herds as fit in a cell are accepted, and more are an error, not a panic
+        let world = parse_input("\u{100}\u{1fd}", herds(254)).unwrap();
+        assert_eq!(world.herd_at(1, 0), Some(253));
+        assert!(parse_input("\u{100}", herds(255)).is_err());
+
+        // Check an empty world is an error rather than a division by zero when stepping
+        assert!(World::new(0, 5, herds(2)).is_err());
+        assert!(World::new(5, 0, herds(2)).is_err());
+        assert!(parse_input("\n\n", herds(2)).is_err());
+    }
+}